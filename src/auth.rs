@@ -0,0 +1,515 @@
+use anyhow::{bail, Context, Result};
+use reqwest::{header::WWW_AUTHENTICATE, RequestBuilder, Response, StatusCode};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    credentials::Credentials,
+    error::PullError,
+    http,
+    retry::{self, RetryPolicy},
+    token_cache::TokenCache,
+};
+
+// No `Debug` on anything holding a secret, so that it can't end up in a log
+#[derive(serde::Deserialize)]
+struct AuthResp {
+    // Some registries only send the older `access_token` field
+    #[serde(alias = "access_token")]
+    token: String,
+    /// Lifetime in seconds, the token spec says to assume 60 when it's missing
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+/// Refresh a token this long before it expires, so that it doesn't run out in flight.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(15);
+
+/// What we authorize registry requests with.
+#[derive(Clone, PartialEq)]
+pub enum Token {
+    /// From the registry's auth server, until it expires
+    Bearer { value: String, expires: Instant },
+    /// For registries without an auth server, which want the login on every request
+    Basic(Credentials),
+}
+
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Bearer { expires, .. } => f
+                .debug_struct("Bearer")
+                .field("value", &"<redacted>")
+                .field("expires", expires)
+                .finish(),
+            Token::Basic(credentials) => f.debug_tuple("Basic").field(credentials).finish(),
+        }
+    }
+}
+
+impl Token {
+    fn expiring(&self) -> bool {
+        match self {
+            Token::Bearer { expires, .. } => Instant::now() + EXPIRY_MARGIN >= *expires,
+            Token::Basic(_) => false,
+        }
+    }
+}
+
+/// Parses a `WWW-Authenticate` challenge like `Bearer realm="...",service="..."` into its
+/// scheme and lowercased parameter names.
+fn parse_challenge(header: &str) -> (String, HashMap<String, String>) {
+    let (scheme, rest) = header.trim().split_once(' ').unwrap_or((header.trim(), ""));
+    let mut params = HashMap::new();
+
+    let mut rest = rest.trim_start();
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim_start();
+
+        let (value, remainder) = match value.strip_prefix('"') {
+            Some(quoted) => quoted_string(quoted),
+            None => {
+                let (value, remainder) = value.split_once(',').unwrap_or((value, ""));
+                (value.trim_end().to_owned(), remainder)
+            }
+        };
+        params.insert(key, value);
+
+        rest = remainder.trim_start().trim_start_matches(',').trim_start();
+    }
+
+    (scheme.to_ascii_lowercase(), params)
+}
+
+/// Reads a quoted string up to its closing quote, undoing backslash escapes the way RFC 7230
+/// has them. Returns the value and whatever follows it.
+fn quoted_string(quoted: &str) -> (String, &str) {
+    let mut value = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return (value, &quoted[index + 1..]),
+            '\\' => value.extend(chars.next().map(|(_, escaped)| escaped)),
+            c => value.push(c),
+        }
+    }
+
+    // Unterminated, take what there is
+    (value, "")
+}
+
+/// The access we ask for, pulling from `repository`.
+fn scope(repository: &str) -> String {
+    format!("repository:{repository}:pull", repository = repository)
+}
+
+/// Gets a pull token the way a 401's `WWW-Authenticate` `challenge` asks for: from the realm it
+/// advertises, logging in with `credentials` if we have any, and retrying per `policy`.
+/// Registries that ask for basic auth get the credentials themselves.
+async fn authenticate(
+    clients: &http::Clients,
+    registry_url: &str,
+    repository: &str,
+    credentials: Option<&Credentials>,
+    challenge: &str,
+    policy: &RetryPolicy,
+) -> Result<Token> {
+    let (scheme, params) = parse_challenge(challenge);
+    match (scheme.as_str(), credentials) {
+        ("bearer", _) => {}
+        ("basic", Some(credentials)) => return Ok(Token::Basic(credentials.clone())),
+        ("basic", None) => {
+            return Err(PullError::Auth {
+                registry: registry_url.to_owned(),
+                reason: "it requires a login, add one to the docker config file".to_owned(),
+            }
+            .into())
+        }
+        _ => bail!("Unsupported auth scheme '{}' from {}", scheme, registry_url),
+    }
+    let realm = params
+        .get("realm")
+        .context("WWW-Authenticate challenge is missing a realm")?;
+
+    let mut query = vec![("scope", scope(repository))];
+    if let Some(service) = params.get("service") {
+        query.push(("service", service.to_owned()));
+    }
+
+    tracing::debug!("Requesting a token from {} with {:?}", realm, query);
+    let issued = Instant::now();
+    // Auth servers time out and rate limit like registries do
+    let response = retry::send_with_retry(policy, || {
+        let request = clients.get(realm).query(&query);
+        match credentials {
+            Some(credentials) => {
+                request.basic_auth(&credentials.username, Some(&credentials.password))
+            }
+            None => request,
+        }
+    })
+    .await?;
+    if !response.status().is_success() {
+        return Err(PullError::Auth {
+            registry: registry_url.to_owned(),
+            reason: format!("{} answered {}", realm, response.status()),
+        }
+        .into());
+    }
+    let auth_res = response.json::<AuthResp>().await?;
+
+    Ok(Token::Bearer {
+        value: auth_res.token,
+        expires: issued + Duration::from_secs(auth_res.expires_in.unwrap_or(60)),
+    })
+}
+
+/// The token we have, if any, and the challenge it was issued for, to renew it by.
+#[derive(Default)]
+struct Auth {
+    token: Option<Token>,
+    challenge: Option<String>,
+}
+
+/// Holds the pull token for one repository. There is none until the registry asks for one by
+/// answering 401, registries without auth never see a token request. It is swapped for a fresh
+/// one shortly before it expires, or when the registry starts rejecting it anyway, so that slow
+/// pulls outlive their token. Docker Hub's only last five minutes.
+pub struct TokenManager {
+    clients: http::Clients,
+    registry_url: String,
+    repository: String,
+    credentials: Option<Credentials>,
+    retry: RetryPolicy,
+    cache: Option<TokenCache>,
+    auth: Mutex<Auth>,
+}
+
+impl TokenManager {
+    pub fn new(
+        clients: &http::Clients,
+        registry_url: &str,
+        repository: &str,
+        credentials: Option<Credentials>,
+        retry: RetryPolicy,
+        cache: Option<TokenCache>,
+    ) -> Self {
+        let mut manager = TokenManager {
+            clients: clients.clone(),
+            registry_url: registry_url.to_owned(),
+            repository: repository.to_owned(),
+            credentials,
+            retry,
+            cache,
+            auth: Mutex::default(),
+        };
+
+        // A cached token that turns out to be revoked gets a 401 and is replaced like any other
+        manager.auth.get_mut().token = manager.cached();
+        manager
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Tokens are only good for the account that asked for them.
+    fn cache_key(&self) -> String {
+        let key = format!("{} {}", self.registry_url, scope(&self.repository));
+        match &self.credentials {
+            Some(credentials) => format!("{} {}", key, credentials.username),
+            None => key,
+        }
+    }
+
+    fn cached(&self) -> Option<Token> {
+        let (value, valid_for) = self.cache.as_ref()?.get(&self.cache_key())?;
+        let token = Token::Bearer {
+            value,
+            expires: Instant::now() + valid_for,
+        };
+        if token.expiring() {
+            return None;
+        }
+        tracing::debug!("Using cached token for {}", self.repository);
+        Some(token)
+    }
+
+    async fn fetch(&self, challenge: &str) -> Result<Token> {
+        let token = authenticate(
+            &self.clients,
+            &self.registry_url,
+            &self.repository,
+            self.credentials.as_ref(),
+            challenge,
+            &self.retry,
+        )
+        .await?;
+
+        // Only the short lived bearer tokens, credentials stay where the user put them
+        if let (Some(cache), Token::Bearer { value, expires }) = (&self.cache, &token) {
+            let valid_for = expires.saturating_duration_since(Instant::now());
+            cache.put(&self.cache_key(), value, valid_for);
+        }
+        Ok(token)
+    }
+
+    /// The token to send, refreshed first if it is about to expire. One from the token cache
+    /// has no challenge to renew it by yet, it is dropped and the registry asked again.
+    async fn current(&self) -> Result<Option<Token>> {
+        let mut auth = self.auth.lock().await;
+        if auth.token.as_ref().is_some_and(Token::expiring) {
+            tracing::debug!(
+                "Token for {} is about to expire, refreshing",
+                self.repository
+            );
+            auth.token = match auth.challenge.clone() {
+                Some(challenge) => Some(self.fetch(&challenge).await?),
+                None => None,
+            };
+        }
+
+        Ok(auth.token.clone())
+    }
+
+    /// Fetches a new token for `challenge`, unless someone else already replaced `stale` in
+    /// the meantime.
+    async fn refresh(&self, stale: Option<&Token>, challenge: &str) -> Result<()> {
+        let mut auth = self.auth.lock().await;
+        if auth.token.as_ref() == stale {
+            auth.token = Some(self.fetch(challenge).await?);
+            auth.challenge = Some(challenge.to_owned());
+        }
+
+        Ok(())
+    }
+
+    /// Sends the request built by `build` with the current token, if we have one. If the
+    /// registry answers 401, a token is fetched the way its challenge says and the request
+    /// retried once. Transient failures are retried per the retry policy.
+    pub async fn send(&self, build: impl Fn() -> RequestBuilder) -> Result<Response> {
+        let response = self.send_authorized(&build).await?;
+        // Only the unverified client stops at redirects, at those to other hosts
+        self.clients
+            .follow_redirect(response, &build, &self.retry)
+            .await
+    }
+
+    async fn send_authorized(&self, build: &impl Fn() -> RequestBuilder) -> Result<Response> {
+        let token = self.current().await?;
+        let response =
+            retry::send_with_retry(&self.retry, || authorize(build(), token.as_ref())).await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .with_context(|| {
+                format!(
+                    "{} answered 401 without a WWW-Authenticate challenge",
+                    response.url()
+                )
+            })?
+            .to_str()?
+            .to_owned();
+        match &token {
+            Some(_) => tracing::debug!("Token rejected by {}, refreshing", response.url()),
+            None => tracing::debug!("{} wants a token", response.url()),
+        }
+        self.refresh(token.as_ref(), &challenge).await?;
+        let token = self.current().await?;
+        retry::send_with_retry(&self.retry, || authorize(build(), token.as_ref())).await
+    }
+}
+
+/// Adds the token to a request, if the registry wants one.
+fn authorize(request: RequestBuilder, token: Option<&Token>) -> RequestBuilder {
+    match token {
+        Some(Token::Bearer { value, .. }) => request.bearer_auth(value),
+        Some(Token::Basic(credentials)) => {
+            request.basic_auth(&credentials.username, Some(&credentials.password))
+        }
+        None => request,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, REPOSITORY};
+    use wiremock::{
+        matchers::{header, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    async fn paths(server: &MockServer) -> Vec<String> {
+        let requests = server.received_requests().await.unwrap();
+        requests.iter().map(|r| r.url.path().to_owned()).collect()
+    }
+
+    #[test]
+    fn challenges_are_split_into_scheme_and_parameters() {
+        let (scheme, params) = parse_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull""#,
+        );
+        assert_eq!(scheme, "bearer");
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/alpine:pull");
+
+        // Unquoted values, spaces after the commas and names in any case
+        let (scheme, params) = parse_challenge(r#"BASIC Realm=registry, charset="UTF-8""#);
+        assert_eq!(scheme, "basic");
+        assert_eq!(params["realm"], "registry");
+        assert_eq!(params["charset"], "UTF-8");
+
+        let (scheme, params) = parse_challenge("Basic");
+        assert_eq!(scheme, "basic");
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn quoted_strings_end_at_their_closing_quote() {
+        assert_eq!(
+            quoted_string(r#"a,b=c",next"#),
+            ("a,b=c".to_owned(), ",next")
+        );
+        assert_eq!(
+            quoted_string(r#"say \"hi\"" "#),
+            (r#"say "hi""#.to_owned(), " ")
+        );
+        assert_eq!(
+            quoted_string("unterminated"),
+            ("unterminated".to_owned(), "")
+        );
+
+        // A quoted comma doesn't end the parameter
+        let (_, params) = parse_challenge(r#"Bearer scope="repository:a:pull,push",realm="r""#);
+        assert_eq!(params["scope"], "repository:a:pull,push");
+        assert_eq!(params["realm"], "r");
+    }
+
+    #[tokio::test]
+    async fn a_registry_without_auth_never_sees_a_token_request() {
+        let server = MockServer::start().await;
+        Mock::given(path("/v2/test/img/manifests/latest"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+
+        let url = format!("{}/v2/test/img/manifests/latest", server.uri());
+        let response = tokens.send(|| client.get(&url)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(paths(&server).await, ["/v2/test/img/manifests/latest"]);
+        let requests = server.received_requests().await.unwrap();
+        assert!(!requests[0].headers.contains_key("authorization"));
+    }
+
+    #[tokio::test]
+    async fn a_token_is_fetched_the_way_the_challenge_says() {
+        let server = MockServer::start().await;
+        let challenge = format!(r#"Bearer realm="{}/token",service="test""#, server.uri());
+        Mock::given(path("/token"))
+            .and(query_param("service", "test"))
+            .and(query_param(
+                "scope",
+                format!("repository:{}:pull", REPOSITORY),
+            ))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({"token": "t"})),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(path("/v2/test/img/manifests/latest"))
+            .and(header("authorization", "Bearer t"))
+            .respond_with(ResponseTemplate::new(200))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/v2/test/img/manifests/latest"))
+            .respond_with(ResponseTemplate::new(401).insert_header("WWW-Authenticate", &challenge))
+            .mount(&server)
+            .await;
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+
+        let url = format!("{}/v2/test/img/manifests/latest", server.uri());
+        for _ in 0..2 {
+            let response = tokens.send(|| client.get(&url)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        // The token is fetched once and then reused
+        assert_eq!(
+            paths(&server).await,
+            [
+                "/v2/test/img/manifests/latest",
+                "/token",
+                "/v2/test/img/manifests/latest",
+                "/v2/test/img/manifests/latest",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn basic_auth_without_a_login_fails_as_such() {
+        let server = MockServer::start().await;
+        Mock::given(path("/v2/test/img/manifests/latest"))
+            .respond_with(
+                ResponseTemplate::new(401).insert_header("WWW-Authenticate", r#"Basic realm="x""#),
+            )
+            .mount(&server)
+            .await;
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+
+        let url = format!("{}/v2/test/img/manifests/latest", server.uri());
+        let err = tokens.send(|| client.get(&url)).await.unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<PullError>(),
+            Some(PullError::Auth { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn token_requests_are_retried() {
+        let server = MockServer::start().await;
+        let challenge = format!(r#"Bearer realm="{}/token""#, server.uri());
+        Mock::given(path("/token"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/token"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({"token": "t"})),
+            )
+            .mount(&server)
+            .await;
+        let client = testing::clients();
+
+        let token = authenticate(
+            &client,
+            &server.uri(),
+            REPOSITORY,
+            None,
+            &challenge,
+            &testing::fast_retries(),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(token, Token::Bearer { value, .. } if value == "t"));
+    }
+}
@@ -0,0 +1,293 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tempfile::NamedTempFile;
+
+/// Root of everything mydocker keeps between runs, `~/.mydocker`.
+pub fn state_dir() -> PathBuf {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join(".mydocker")
+}
+
+/// A download in progress, kept as `<digest>.partial` so a later pull can resume it but it is
+/// never mistaken for a verified entry. Holds an exclusive lock on the file while alive.
+pub struct PartialBlob {
+    pub file: File,
+    path: PathBuf,
+}
+
+/// When each blob was last used, keyed by digest. Kept by us instead of relying on atime, which
+/// is often disabled or coarse.
+const ACCESS_FILE: &str = "access.json";
+
+/// A verified blob in the cache.
+pub struct CacheEntry {
+    pub digest: String,
+    pub size: u64,
+    /// Seconds since the epoch
+    pub last_used: u64,
+}
+
+/// What `prune` removed, or would have removed.
+#[derive(Default)]
+pub struct PruneReport {
+    pub blobs: usize,
+    pub bytes: u64,
+}
+
+/// Content addressed store of verified blobs, one file per digest.
+pub struct BlobCache {
+    dir: PathBuf,
+}
+
+impl BlobCache {
+    pub fn new(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create cache directory {}", dir.display()))?;
+
+        Ok(BlobCache {
+            dir: dir.to_owned(),
+        })
+    }
+
+    /// `~/.mydocker/layers`, next to the rest of our local state.
+    pub fn default_dir() -> PathBuf {
+        state_dir().join("layers")
+    }
+
+    /// The cache in `dir`, or in the default directory.
+    pub fn open(dir: Option<&Path>) -> Result<Self> {
+        match dir {
+            Some(dir) => BlobCache::new(dir),
+            None => BlobCache::new(&BlobCache::default_dir()),
+        }
+    }
+
+    fn path(&self, digest: &str) -> PathBuf {
+        self.dir.join(digest)
+    }
+
+    /// The cached file for `digest`, if we already have it.
+    pub fn get(&self, digest: &str) -> Option<PathBuf> {
+        let path = self.path(digest);
+        path.is_file().then_some(path)
+    }
+
+    /// Deletes the blob for `digest`, returning whether there was one.
+    pub fn remove(&self, digest: &str) -> Result<bool> {
+        match std::fs::remove_file(self.path(digest)) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => {
+                Err(err).with_context(|| format!("Failed to remove {} from the cache", digest))
+            }
+        }
+    }
+
+    /// A temp file on the same filesystem as the cache, to download a blob into before it is
+    /// verified and moved into place with `persist`.
+    pub fn temp_file(&self) -> Result<NamedTempFile> {
+        Ok(NamedTempFile::new_in(&self.dir)?)
+    }
+
+    /// Stores an already verified blob under `digest`. Renaming the temp file into place means
+    /// readers never see a partial entry.
+    pub fn persist(&self, digest: &str, temp_file: NamedTempFile) -> Result<PathBuf> {
+        let path = self.path(digest);
+        temp_file
+            .persist(&path)
+            .with_context(|| format!("Failed to store {} in the cache", digest))?;
+        self.touch(digest);
+
+        Ok(path)
+    }
+
+    /// The partial download of `digest`, created empty if there is none. Returns `None` if
+    /// another process is downloading the same blob right now.
+    pub fn partial(&self, digest: &str) -> Result<Option<PartialBlob>> {
+        let path = self.dir.join(format!("{}.partial", digest));
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(PartialBlob { file, path }))
+    }
+
+    /// Moves a fully downloaded and verified partial blob into place under `digest`.
+    pub fn complete(&self, digest: &str, partial: PartialBlob) -> Result<PathBuf> {
+        let path = self.path(digest);
+        std::fs::rename(&partial.path, &path)
+            .with_context(|| format!("Failed to store {} in the cache", digest))?;
+        self.touch(digest);
+
+        Ok(path)
+    }
+
+    /// Throws away a partial blob that turned out to be corrupt, so it isn't resumed.
+    pub fn discard(&self, partial: PartialBlob) -> Result<()> {
+        std::fs::remove_file(&partial.path)
+            .with_context(|| format!("Failed to remove {}", partial.path.display()))
+    }
+
+    fn read_access(&self) -> HashMap<String, u64> {
+        std::fs::read(self.dir.join(ACCESS_FILE))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    fn write_access(&self, access: &HashMap<String, u64>) -> Result<()> {
+        let temp_file = self.temp_file()?;
+        serde_json::to_writer(temp_file.as_file(), access)?;
+        temp_file.persist(self.dir.join(ACCESS_FILE))?;
+
+        Ok(())
+    }
+
+    /// Records that `digest` was just used. Only bookkeeping for eviction, so failing to write
+    /// it is never worth failing a pull over.
+    pub fn touch(&self, digest: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut access = self.read_access();
+        access.insert(digest.to_owned(), now);
+        if let Err(err) = self.write_access(&access) {
+            tracing::warn!("Failed to record use of {}: {:#}", digest, err);
+        }
+    }
+
+    /// Every verified blob, least recently used first. Blobs we have no record of count by
+    /// their modification time.
+    pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let access = self.read_access();
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)
+            .with_context(|| format!("Failed to read cache directory {}", self.dir.display()))?
+        {
+            let entry = entry?;
+            let digest = entry.file_name().to_string_lossy().into_owned();
+            let is_blob = digest
+                .split_once(':')
+                .is_some_and(|(_, hex)| hex.chars().all(|c| c.is_ascii_hexdigit()));
+            if !is_blob {
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            let modified = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            entries.push(CacheEntry {
+                last_used: access.get(&digest).copied().unwrap_or(modified),
+                size: metadata.len(),
+                digest,
+            });
+        }
+        entries.sort_by_key(|entry| entry.last_used);
+
+        Ok(entries)
+    }
+
+    /// Evicts blobs not in `in_use`, least recently used first, until the cache fits in
+    /// `max_size` bytes. Without a budget every unused blob goes, along with downloads that
+    /// were abandoned halfway.
+    pub fn prune(
+        &self,
+        in_use: &HashSet<String>,
+        max_size: Option<u64>,
+        dry_run: bool,
+    ) -> Result<PruneReport> {
+        let entries = self.entries()?;
+        let mut size: u64 = entries.iter().map(|entry| entry.size).sum();
+        let mut report = PruneReport::default();
+
+        for entry in &entries {
+            if max_size.is_some_and(|max_size| size <= max_size) {
+                break;
+            }
+            if in_use.contains(&entry.digest) {
+                continue;
+            }
+
+            if !dry_run {
+                self.remove(&entry.digest)?;
+            }
+            size -= entry.size;
+            report.blobs += 1;
+            report.bytes += entry.size;
+        }
+
+        if max_size.is_none() {
+            for entry in std::fs::read_dir(&self.dir)? {
+                let path = entry?.path();
+                if path
+                    .extension()
+                    .is_none_or(|extension| extension != "partial")
+                {
+                    continue;
+                }
+                let digest = path.file_stem().unwrap_or_default().to_string_lossy();
+                // A partial someone holds the lock on is still being downloaded
+                if let Some(partial) = self.partial(&digest)? {
+                    let bytes = partial.file.metadata()?.len();
+                    if !dry_run {
+                        self.discard(partial)?;
+                    }
+                    report.blobs += 1;
+                    report.bytes += bytes;
+                }
+            }
+        }
+
+        if !dry_run {
+            let mut access = self.read_access();
+            let remaining: HashSet<_> = self.entries()?.into_iter().map(|e| e.digest).collect();
+            access.retain(|digest, _| remaining.contains(digest));
+            self.write_access(&access)?;
+        }
+
+        Ok(report)
+    }
+}
+
+/// Parses sizes like `512M`, `10G` or `1.5GiB`, in binary units. A plain number is bytes.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = match number.parse() {
+        Ok(number) => number,
+        Err(_) => bail!("Invalid size '{}'", s),
+    };
+
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        unit => bail!("Unknown size unit '{}' in '{}'", unit, s),
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
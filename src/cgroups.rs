@@ -0,0 +1,200 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    fs::File,
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Our groups live below this one, next to whatever else the host has.
+const PARENT: &str = "mydocker";
+
+/// The scheduling period CPU quotas are given in, Docker's 100ms.
+const CPU_PERIOD_US: u64 = 100_000;
+/// The smallest quota the kernel takes.
+const MIN_CPU_QUOTA_US: u64 = 1_000;
+
+/// Resource limits for the container, each `None` when not limited.
+#[derive(Debug, Default)]
+pub struct Limits {
+    /// Bytes of memory, `memory.max`
+    pub memory: Option<u64>,
+    /// How many CPUs worth of time, as a quota per period in `cpu.max`
+    pub cpus: Option<f64>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_none() && self.cpus.is_none()
+    }
+
+    fn controllers(&self) -> Vec<&'static str> {
+        let mut controllers = Vec::new();
+        if self.memory.is_some() {
+            controllers.push("memory");
+        }
+        if self.cpus.is_some() {
+            controllers.push("cpu");
+        }
+        controllers
+    }
+}
+
+/// Parses `--cpus`, a positive number of CPUs that may be fractional like `0.5`.
+pub fn parse_cpus(s: &str) -> Result<f64> {
+    let cpus: f64 = s.trim().parse().context("expected a number like 1.5")?;
+    if !cpus.is_finite() || cpus <= 0.0 {
+        bail!("the number of CPUs has to be positive");
+    }
+
+    Ok(cpus)
+}
+
+/// `cpu.max` for `cpus`, e.g. `50000 100000` for half a CPU.
+fn cpu_max(cpus: f64) -> String {
+    let quota = (cpus * CPU_PERIOD_US as f64).round() as u64;
+    format!("{} {}", quota.max(MIN_CPU_QUOTA_US), CPU_PERIOD_US)
+}
+
+/// A cgroup v2 group of the container's own, removed again when dropped. Note that
+/// `std::process::exit` doesn't run destructors.
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+fn write(path: &Path, value: &str) -> Result<()> {
+    std::fs::write(path, value)
+        .with_context(|| format!("Failed to write '{}' to {}", value, path.display()))
+}
+
+/// Makes `controllers` available to the children of `dir`.
+fn enable_controllers(dir: &Path, controllers: &[&str]) -> Result<()> {
+    let path = dir.join("cgroup.subtree_control");
+    let enabled = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    for controller in controllers {
+        if !enabled.split_whitespace().any(|c| c == *controller) {
+            write(&path, &format!("+{}", controller))?;
+        }
+    }
+
+    Ok(())
+}
+
+impl Cgroup {
+    /// Creates `/sys/fs/cgroup/mydocker/<name>` with `limits` applied. Needs cgroup v2 with the
+    /// controllers for the limits available to us, there is no falling back to v1.
+    pub fn create(name: &str, limits: &Limits) -> Result<Self> {
+        let root = Path::new(CGROUP_ROOT);
+        let available = match std::fs::read_to_string(root.join("cgroup.controllers")) {
+            Ok(available) => available,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => bail!(
+                "Resource limits need cgroup v2, but {} isn't a cgroup v2 hierarchy",
+                CGROUP_ROOT
+            ),
+            Err(err) => return Err(err).context("Failed to read the available cgroup controllers"),
+        };
+        let controllers = limits.controllers();
+        for controller in &controllers {
+            if !available.split_whitespace().any(|c| c == *controller) {
+                bail!(
+                    "The cgroup {} controller isn't available, it may not be delegated to us",
+                    controller
+                );
+            }
+        }
+
+        enable_controllers(root, &controllers)?;
+        let parent = root.join(PARENT);
+        std::fs::create_dir_all(&parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+        enable_controllers(&parent, &controllers)?;
+
+        let path = parent.join(name);
+        std::fs::create_dir(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        let cgroup = Cgroup { path };
+
+        if let Some(memory) = limits.memory {
+            write(&cgroup.path.join("memory.max"), &memory.to_string())?;
+        }
+        if let Some(cpus) = limits.cpus {
+            write(&cgroup.path.join("cpu.max"), &cpu_max(cpus))?;
+        }
+
+        Ok(cgroup)
+    }
+
+    /// The group's directory in the host's cgroup hierarchy.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `cgroup.procs`, opened up front for the container's process to join the group with
+    /// `join` once it is forked, before it execs.
+    pub fn procs(&self) -> Result<File> {
+        let path = self.path.join("cgroup.procs");
+        File::options()
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))
+    }
+}
+
+/// Moves the calling process into the group `procs` belongs to. Safe to call between fork and
+/// exec.
+pub fn join(procs: &File) -> io::Result<()> {
+    // `0` stands for whoever writes it
+    match unsafe { libc::write(procs.as_raw_fd(), b"0".as_ptr().cast(), 1) } {
+        1 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // The kernel is still reaping the rest of the container's PID namespace for a moment
+        // after its first process exited, until then the group isn't empty
+        for _ in 0..20 {
+            match std::fs::remove_dir(&self.path) {
+                Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {
+                    std::thread::sleep(Duration::from_millis(50))
+                }
+                Err(err) => {
+                    tracing::warn!("Failed to remove {}: {}", self.path.display(), err);
+                    return;
+                }
+                Ok(()) => return,
+            }
+        }
+        tracing::warn!(
+            "Failed to remove {}, it is still in use",
+            self.path.display()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpus_become_a_quota_per_period() {
+        assert_eq!(cpu_max(0.5), "50000 100000");
+        assert_eq!(cpu_max(1.0), "100000 100000");
+        assert_eq!(cpu_max(2.25), "225000 100000");
+        // Below what the kernel takes
+        assert_eq!(cpu_max(0.001), "1000 100000");
+    }
+
+    #[test]
+    fn cpus_are_positive_numbers() {
+        assert_eq!(parse_cpus(" 1.5 ").unwrap(), 1.5);
+        assert!(parse_cpus("0").is_err());
+        assert!(parse_cpus("-1").is_err());
+        assert!(parse_cpus("inf").is_err());
+        assert!(parse_cpus("half").is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+use docker_starter_rust::{cache, config, PullOptions};
+
+use crate::{cgroups, mounts};
+
+#[derive(Parser, Debug)]
+#[command(name = "mydocker", version, about = "A tiny docker clone")]
+pub struct Cli {
+    /// Log what is going on, `-v` for the main steps and `-vv` for every request. `RUST_LOG`
+    /// takes precedence
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Pull an image and run a command inside it
+    Run(Box<RunArgs>),
+    /// Pull an image and unpack it without running anything
+    Pull(Box<PullArgs>),
+    /// List the images pulled so far
+    Images(ImagesArgs),
+    /// Remove a local image and the layers no other image uses
+    Rmi(RmiArgs),
+    /// Manage local state
+    System {
+        #[command(subcommand)]
+        command: SystemCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SystemCommand {
+    /// Remove cached layers that no local image uses
+    Prune(PruneArgs),
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Network {
+    /// A network namespace of its own with only a loopback interface
+    None,
+    /// The host's network, unisolated
+    Host,
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub pull: PullOptions,
+
+    /// Set an environment variable in the container, `KEY=VALUE` or `KEY` to pass ours on
+    #[arg(short, long = "env", value_name = "KEY=VALUE", value_parser = config::validate_env)]
+    pub env: Vec<String>,
+
+    /// Read environment variables from a file of `KEY=VALUE` lines, `--env` wins over these
+    #[arg(long = "env-file", value_name = "FILE")]
+    pub env_files: Vec<PathBuf>,
+
+    /// Bind mount an existing host directory or file into the container, read-only with `:ro`.
+    /// Relative host paths are taken from the current directory
+    #[arg(short, long = "volume", value_name = "HOST:CONTAINER[:ro]", value_parser = mounts::parse_volume)]
+    pub volumes: Vec<mounts::Volume>,
+
+    /// Mount an empty tmpfs at this path in the container, with options like `size=64m,noexec`.
+    /// `/tmp` and `/run` always get one
+    #[arg(long, value_name = "PATH[:OPTIONS]", value_parser = mounts::parse_tmpfs)]
+    pub tmpfs: Vec<mounts::Tmpfs>,
+
+    /// Make the container's root read-only, only volumes and `--tmpfs` mounts stay writable
+    #[arg(long)]
+    pub read_only: bool,
+
+    /// Directory to run the command in, instead of the image's working directory
+    #[arg(short = 'w', long, value_name = "DIR")]
+    pub workdir: Option<String>,
+
+    /// Network to connect the container to
+    #[arg(long, value_enum, default_value_t = Network::None)]
+    pub network: Network,
+
+    /// Seconds the container gets to exit after a forwarded SIGINT or SIGTERM before it is killed
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub stop_timeout: u64,
+
+    /// Leave the container's root directory in place after it exits, for debugging
+    #[arg(long, conflicts_with = "name")]
+    pub keep_root: bool,
+
+    /// Keep the container's root in `~/.mydocker/containers/<NAME>`, where later runs with the
+    /// same name reuse it without unpacking the image again
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Memory limit for the container, e.g. `512M`. Needs cgroup v2
+    #[arg(short, long, value_name = "SIZE", value_parser = cache::parse_size)]
+    pub memory: Option<u64>,
+
+    /// How many CPUs the container may use, e.g. `0.5` for half of one. Needs cgroup v2
+    #[arg(long, value_parser = cgroups::parse_cpus)]
+    pub cpus: Option<f64>,
+
+    /// Print the image's platform, digest, layers and config as JSON instead of running it
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Remove a named container once it exits, unnamed ones always are
+    #[arg(long)]
+    pub rm: bool,
+
+    /// Hostname of the container (defaults to its ID)
+    #[arg(long)]
+    pub hostname: Option<String>,
+
+    /// User to run as, `name`, `uid` or with a group as `name:group` (defaults to the image's)
+    #[arg(short, long, value_name = "USER[:GROUP]")]
+    pub user: Option<String>,
+
+    /// Image reference, e.g. `ubuntu:latest` or `ubuntu@sha256:...`
+    pub image: String,
+
+    /// Run this instead of the image's entrypoint, `""` to run the command as is
+    #[arg(long, value_name = "COMMAND")]
+    pub entrypoint: Option<String>,
+
+    /// Command to run and its arguments (defaults to the image's command)
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct PullArgs {
+    #[command(flatten)]
+    pub pull: PullOptions,
+
+    /// Image reference, e.g. `ubuntu:latest` or `ubuntu@sha256:...`
+    pub image: String,
+}
+
+#[derive(Args, Debug)]
+pub struct ImagesArgs {
+    /// Directory of the layer cache (defaults to `~/.mydocker/layers`)
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct RmiArgs {
+    /// Image reference, e.g. `ubuntu:latest` or `ubuntu@sha256:...`
+    pub image: String,
+
+    /// Remove the image even if a container still uses it
+    #[arg(short, long)]
+    pub force: bool,
+
+    /// Directory of the layer cache (defaults to `~/.mydocker/layers`)
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct PruneArgs {
+    /// Only evict least recently used layers until the cache fits, e.g. `10G`
+    #[arg(long, value_name = "SIZE", value_parser = cache::parse_size)]
+    pub cache_max_size: Option<u64>,
+
+    /// Show what would be removed without removing it
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Directory of the layer cache (defaults to `~/.mydocker/layers`)
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+}
@@ -0,0 +1,283 @@
+use anyhow::Context;
+use std::{collections::BTreeMap, path::Path};
+
+/// The parts of an image config blob we use. Everything in it is optional, `config` can even be
+/// `null` for images built from scratch.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default)]
+pub struct ImageConfig {
+    #[serde(default)]
+    pub config: Option<ContainerConfig>,
+}
+
+/// Defaults for containers run from the image, in Docker's capitalized field names.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default)]
+pub struct ContainerConfig {
+    #[serde(rename = "Env", default)]
+    pub env: Option<Vec<String>>,
+    #[serde(rename = "Entrypoint", default, deserialize_with = "string_or_list")]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(rename = "Cmd", default, deserialize_with = "string_or_list")]
+    pub cmd: Option<Vec<String>>,
+    #[serde(rename = "WorkingDir", default)]
+    pub working_dir: Option<String>,
+    #[serde(rename = "User", default)]
+    pub user: Option<String>,
+    /// Ports the image listens on, as `80/tcp` keys mapping to empty objects
+    #[serde(rename = "ExposedPorts", default)]
+    pub exposed_ports: Option<BTreeMap<String, serde_json::Value>>,
+}
+
+/// Docker accepts a plain string wherever it takes a command line. That is the shell form, run
+/// through `/bin/sh -c` like a Dockerfile's `CMD echo $HOME` is.
+fn string_or_list<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    let value: Option<StringOrList> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(value.map(|value| match value {
+        StringOrList::String(string) => vec!["/bin/sh".to_owned(), "-c".to_owned(), string],
+        StringOrList::List(list) => list,
+    }))
+}
+
+/// What Docker gives containers whose image doesn't set a `PATH`.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+impl ImageConfig {
+    /// The image's environment as key/value pairs, with a default `PATH` if it has none.
+    /// Entries without an `=` are ignored, Docker doesn't produce them.
+    pub fn env(&self) -> Vec<(String, String)> {
+        let mut env: Vec<_> = self
+            .config
+            .as_ref()
+            .and_then(|config| config.env.as_ref())
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.split_once('='))
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect();
+        if !env.iter().any(|(key, _)| key == "PATH") {
+            env.push(("PATH".to_owned(), DEFAULT_PATH.to_owned()));
+        }
+        env
+    }
+
+    /// The directory to run the command in, `/` unless the image says otherwise. Docker writes
+    /// an empty string rather than leaving it out.
+    pub fn working_dir(&self) -> &str {
+        self.config
+            .as_ref()
+            .and_then(|config| config.working_dir.as_deref())
+            .filter(|dir| !dir.is_empty())
+            .unwrap_or("/")
+    }
+
+    /// The user to run as, `None` for root. Like `WorkingDir` it may be an empty string.
+    pub fn user(&self) -> Option<&str> {
+        self.config
+            .as_ref()
+            .and_then(|config| config.user.as_deref())
+            .filter(|user| !user.is_empty())
+    }
+
+    /// The ports the image says it listens on, like `80/tcp`, sorted. Only informational, we
+    /// don't publish anything.
+    pub fn exposed_ports(&self) -> Vec<&str> {
+        self.config
+            .as_ref()
+            .and_then(|config| config.exposed_ports.as_ref())
+            .into_iter()
+            .flat_map(|ports| ports.keys())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// The command line to run, following Docker: `command` replaces the image's `Cmd` but
+    /// still goes after its `Entrypoint`. Overriding the entrypoint drops the image's `Cmd`
+    /// too, and an empty override drops the entrypoint altogether.
+    pub fn command_line(&self, entrypoint: Option<&str>, command: &[String]) -> Vec<String> {
+        let config = self.config.as_ref();
+        let (entrypoint, cmd) = match entrypoint {
+            Some("") => (Vec::new(), Vec::new()),
+            Some(entrypoint) => (vec![entrypoint.to_owned()], Vec::new()),
+            None => (
+                config
+                    .and_then(|config| config.entrypoint.clone())
+                    .unwrap_or_default(),
+                config
+                    .and_then(|config| config.cmd.clone())
+                    .unwrap_or_default(),
+            ),
+        };
+
+        let cmd = match command.is_empty() {
+            true => cmd,
+            false => command.to_vec(),
+        };
+        entrypoint.into_iter().chain(cmd).collect()
+    }
+}
+
+/// Checks a `--env` value on the command line, so that a typo fails before anything is pulled.
+/// Names can be anything but empty or containing whitespace, like in Docker.
+pub fn validate_env(entry: &str) -> anyhow::Result<String> {
+    let key = entry.split_once('=').map_or(entry, |(key, _)| key);
+    if key.is_empty() {
+        anyhow::bail!("the variable name is empty, expected KEY=VALUE or KEY");
+    }
+    if key.contains(char::is_whitespace) {
+        anyhow::bail!("variable name '{}' contains whitespace", key);
+    }
+
+    Ok(entry.to_owned())
+}
+
+/// Parses a `--env` value: `KEY=VALUE`, or just `KEY` to pass the variable through from our own
+/// environment. Returns `None` for a bare key that isn't set here.
+pub fn parse_env(entry: &str) -> Option<(String, String)> {
+    match entry.split_once('=') {
+        Some((key, value)) => Some((key.to_owned(), value.to_owned())),
+        None => std::env::var(entry)
+            .ok()
+            .map(|value| (entry.to_owned(), value)),
+    }
+}
+
+/// Reads a `--env-file`: one `KEY=VALUE` or bare `KEY` per line like `--env` takes them, with
+/// blank lines and `#` comments skipped. Values are taken as is, quotes included, like Docker.
+pub fn read_env_file(path: &Path) -> anyhow::Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read env file {}", path.display()))?;
+
+    let mut entries = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line).trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = validate_env(line)
+            .with_context(|| format!("{} line {}", path.display(), number + 1))?;
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: serde_json::Value) -> ImageConfig {
+        serde_json::from_value(serde_json::json!({ "config": json })).unwrap()
+    }
+
+    #[test]
+    fn runs_shell_form_commands_through_the_shell() {
+        let config = config(serde_json::json!({
+            "Entrypoint": "exec server --port $PORT",
+            "Cmd": "echo $HOME",
+        }));
+        let config = config.config.unwrap();
+        assert_eq!(
+            config.entrypoint.unwrap(),
+            ["/bin/sh", "-c", "exec server --port $PORT"]
+        );
+        assert_eq!(config.cmd.unwrap(), ["/bin/sh", "-c", "echo $HOME"]);
+    }
+
+    #[test]
+    fn takes_exec_form_commands_as_they_are() {
+        let config = config(serde_json::json!({"Cmd": ["echo", "$HOME"], "Entrypoint": null}));
+        let config = config.config.unwrap();
+        assert_eq!(config.cmd.unwrap(), ["echo", "$HOME"]);
+        assert_eq!(config.entrypoint, None);
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn the_command_line_is_the_entrypoint_followed_by_the_command() {
+        let entrypoint_only = config(serde_json::json!({"Entrypoint": ["nginx", "-g"]}));
+        assert_eq!(entrypoint_only.command_line(None, &[]), ["nginx", "-g"]);
+        assert_eq!(
+            entrypoint_only.command_line(None, &args(&["daemon off;"])),
+            ["nginx", "-g", "daemon off;"]
+        );
+
+        let cmd_only = config(serde_json::json!({"Cmd": ["sh"]}));
+        assert_eq!(cmd_only.command_line(None, &[]), ["sh"]);
+        assert_eq!(
+            cmd_only.command_line(None, &args(&["ls", "/"])),
+            ["ls", "/"]
+        );
+
+        let both = config(serde_json::json!({"Entrypoint": ["python"], "Cmd": ["app.py"]}));
+        assert_eq!(both.command_line(None, &[]), ["python", "app.py"]);
+        assert_eq!(both.command_line(None, &args(&["-V"])), ["python", "-V"]);
+        // A new entrypoint drops the image's Cmd, an empty one the entrypoint as well
+        assert_eq!(both.command_line(Some("pypy"), &[]), ["pypy"]);
+        assert_eq!(both.command_line(Some(""), &args(&["sh"])), ["sh"]);
+
+        assert!(config(serde_json::json!({}))
+            .command_line(None, &[])
+            .is_empty());
+    }
+
+    fn env_file(contents: &str) -> anyhow::Result<Vec<String>> {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), contents).unwrap();
+        read_env_file(file.path())
+    }
+
+    #[test]
+    fn env_files_take_lines_as_they_are() {
+        let entries = env_file(
+            "# comment\r\nURL=postgres://db/app?sslmode=disable\r\n\r\n  GREETING=hello world \nQUOTED=\"x\"\nPASSTHROUGH\n",
+        )
+        .unwrap();
+        assert_eq!(
+            entries,
+            [
+                "URL=postgres://db/app?sslmode=disable",
+                "GREETING=hello world ",
+                "QUOTED=\"x\"",
+                "PASSTHROUGH",
+            ]
+        );
+        assert_eq!(
+            parse_env(&entries[0]),
+            Some((
+                "URL".to_owned(),
+                "postgres://db/app?sslmode=disable".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn env_file_errors_name_the_line() {
+        let err = env_file("A=1\nMY VAR=2\n").unwrap_err();
+        assert!(format!("{:#}", err).contains("line 2"), "{:#}", err);
+        assert!(env_file("=1\n").is_err());
+    }
+
+    #[test]
+    fn exposed_ports_are_listed_sorted() {
+        let one = config(serde_json::json!({"ExposedPorts": {"80/tcp": {}}}));
+        assert_eq!(one.exposed_ports(), ["80/tcp"]);
+
+        let two = config(serde_json::json!({"ExposedPorts": {"53/udp": {}, "443/tcp": {}}}));
+        assert_eq!(two.exposed_ports(), ["443/tcp", "53/udp"]);
+
+        let none = config(serde_json::json!({"ExposedPorts": null}));
+        assert!(none.exposed_ports().is_empty());
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    fs::File,
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use docker_starter_rust::{cache, ImageConfig, PulledImage};
+
+/// A container run under a name, kept in `~/.mydocker/containers/<name>` so that its root
+/// outlives the run and the next run with the same name picks it up instead of unpacking the
+/// image again. Next to the root are the digest of its image, which `rmi` checks, and the image
+/// config, both only written once the root is complete, and the layers unpacked into it so far.
+pub struct NamedContainer {
+    name: String,
+    dir: PathBuf,
+    /// Held for as long as we run the container, another run of it sees the lock taken
+    _lock: File,
+}
+
+/// Docker's rule for container names.
+fn validate_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+    if !valid {
+        bail!(
+            "Invalid container name '{}', only [a-zA-Z0-9][a-zA-Z0-9_.-]* is allowed",
+            name
+        );
+    }
+
+    Ok(())
+}
+
+impl NamedContainer {
+    pub fn lock(name: &str) -> Result<Self> {
+        validate_name(name)?;
+        let dir = cache::state_dir().join("containers").join(name);
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+
+        let lock = File::create(dir.join("lock"))?;
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                bail!("Container {} is already running", name);
+            }
+            return Err(err).context("Failed to lock the container");
+        }
+
+        Ok(NamedContainer {
+            name: name.to_owned(),
+            dir,
+            _lock: lock,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn root(&self) -> PathBuf {
+        self.dir.join("rootfs")
+    }
+
+    fn config_path(&self) -> PathBuf {
+        self.dir.join("config.json")
+    }
+
+    fn image_path(&self) -> PathBuf {
+        self.dir.join("image")
+    }
+
+    /// The config of the image in the root, if an earlier run left a complete one.
+    pub fn config(&self) -> Result<Option<ImageConfig>> {
+        if !self.image_path().exists() {
+            return Ok(None);
+        }
+        let path = self.config_path();
+        let config = match std::fs::read(&path) {
+            Ok(config) => config,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+
+        serde_json::from_slice(&config)
+            .map(Some)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// The record of the layers in the root, for pulling into it to resume a run that didn't
+    /// finish unpacking rather than start over.
+    pub fn applied_layers(&self) -> PathBuf {
+        self.dir.join("layers.json")
+    }
+
+    /// Forgets that the root was complete, before pulling into it again. What a run that didn't
+    /// finish unpacking left behind stays, the pull picks it up going by `applied_layers`.
+    pub fn reset(&self) -> Result<()> {
+        for path in [self.image_path(), self.config_path()] {
+            remove_if_exists(&path, |path| std::fs::remove_file(path))?;
+        }
+        Ok(())
+    }
+
+    /// Marks the root as complete, after `image` has been unpacked into it.
+    pub fn record(&self, image: &PulledImage) -> Result<()> {
+        std::fs::write(self.config_path(), serde_json::to_vec(&image.config)?)?;
+        std::fs::write(self.image_path(), &image.digest)?;
+        Ok(())
+    }
+
+    /// Removes what is left of the container once its root is gone.
+    pub fn remove(self) -> Result<()> {
+        std::fs::remove_dir_all(&self.dir)
+            .with_context(|| format!("Failed to remove {}", self.dir.display()))
+    }
+}
+
+fn remove_if_exists(path: &Path, remove: fn(&Path) -> io::Result<()>) -> Result<()> {
+    match remove(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("Failed to remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,205 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use crate::reference::DEFAULT_REGISTRY;
+
+/// Key docker uses for Docker Hub in `config.json`, for historical reasons.
+const DOCKER_HUB_CONFIG_KEY: &str = "https://index.docker.io/v1/";
+
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(serde::Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    creds_store: Option<String>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(serde::Deserialize)]
+struct AuthEntry {
+    auth: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperResponse {
+    username: String,
+    secret: String,
+}
+
+/// `$DOCKER_CONFIG/config.json`, or `~/.docker/config.json` like the docker CLI.
+fn config_path() -> Option<PathBuf> {
+    match std::env::var_os("DOCKER_CONFIG") {
+        Some(dir) => Some(PathBuf::from(dir).join("config.json")),
+        None => {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".docker/config.json"))
+        }
+    }
+}
+
+/// Reduces a config key like `https://ghcr.io/v1/` to the registry host it names.
+fn normalize_host(key: &str) -> &str {
+    let host = key
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let host = host.split('/').next().unwrap_or(host);
+
+    match host {
+        "docker.io" | "index.docker.io" | "registry-1.docker.io" => DEFAULT_REGISTRY,
+        host => host,
+    }
+}
+
+/// Asks a `docker-credential-<helper>` program for the credentials of `server`.
+fn run_helper(helper: &str, server: &str) -> Result<Option<Credentials>> {
+    let program = format!("docker-credential-{}", helper);
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Tried to run credential helper '{}'", program))?;
+
+    child
+        .stdin
+        .take()
+        .context("Credential helper has no stdin")?
+        .write_all(server.as_bytes())?;
+    let output = child.wait_with_output()?;
+
+    // Helpers exit non-zero when they have nothing stored for the server
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let response: HelperResponse = serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Credential helper '{}' returned invalid JSON", program))?;
+    Ok(Some(Credentials {
+        username: response.username,
+        password: response.secret,
+    }))
+}
+
+fn decode_auth(key: &str, auth: &str) -> Result<Credentials> {
+    let decoded = base64::decode(auth.trim())
+        .with_context(|| format!("Invalid base64 in auth entry for '{}'", key))?;
+    let decoded = String::from_utf8(decoded)
+        .with_context(|| format!("Auth entry for '{}' is not valid UTF-8", key))?;
+
+    match decoded.split_once(':') {
+        Some((username, password)) => Ok(Credentials {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }),
+        None => bail!("Auth entry for '{}' is not in user:password form", key),
+    }
+}
+
+/// Credentials passed explicitly, which win over the docker config: `--username` or
+/// `MYDOCKER_USERNAME`, with the password from stdin or `MYDOCKER_PASSWORD` so that it never
+/// shows up in `ps`.
+pub fn explicit_credentials(
+    username: Option<&str>,
+    password_stdin: bool,
+) -> Result<Option<Credentials>> {
+    let username = username
+        .map(str::to_owned)
+        .or_else(|| std::env::var("MYDOCKER_USERNAME").ok());
+    let Some(username) = username else {
+        if password_stdin {
+            bail!("--password-stdin needs a --username");
+        }
+        return Ok(None);
+    };
+
+    let password = if password_stdin {
+        let mut password = String::new();
+        std::io::stdin()
+            .read_line(&mut password)
+            .context("Failed to read the password from stdin")?;
+        password.trim_end_matches(['\r', '\n']).to_owned()
+    } else {
+        std::env::var("MYDOCKER_PASSWORD").ok().with_context(|| {
+            format!(
+                "No password for '{}', pass one with --password-stdin or MYDOCKER_PASSWORD",
+                username
+            )
+        })?
+    };
+
+    Ok(Some(Credentials { username, password }))
+}
+
+/// Looks up credentials for `registry` in the docker config file. Returns `None` when there is
+/// no config file or no entry for the registry, so the pull can go ahead anonymously. A broken
+/// config does the same: plenty of public images can still be pulled without it.
+pub fn load_credentials(registry: &str) -> Option<Credentials> {
+    match read_credentials(registry) {
+        Ok(credentials) => credentials,
+        Err(err) => {
+            tracing::debug!("Pulling anonymously: {:#}", err);
+            None
+        }
+    }
+}
+
+fn read_credentials(registry: &str) -> Result<Option<Credentials>> {
+    let path = match config_path() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(None),
+    };
+
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let config: DockerConfig = serde_json::from_str(&content)
+        .with_context(|| format!("Malformed docker config {}", path.display()))?;
+
+    let registry = normalize_host(registry);
+    let server = if registry == DEFAULT_REGISTRY {
+        DOCKER_HUB_CONFIG_KEY
+    } else {
+        registry
+    };
+
+    // Per-registry helpers win over the global store, which wins over inline auths
+    let helper = config
+        .cred_helpers
+        .iter()
+        .find(|(key, _)| normalize_host(key) == registry)
+        .map(|(_, helper)| helper)
+        .or(config.creds_store.as_ref());
+    if let Some(helper) = helper {
+        if let Some(credentials) = run_helper(helper, server)? {
+            return Ok(Some(credentials));
+        }
+    }
+
+    config
+        .auths
+        .iter()
+        .find(|(key, _)| normalize_host(key) == registry)
+        .and_then(|(key, entry)| entry.auth.as_deref().map(|auth| decode_auth(key, auth)))
+        .transpose()
+}
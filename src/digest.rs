@@ -0,0 +1,67 @@
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::error::PullError;
+
+/// The digest algorithms OCI registries use.
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+/// Hashes content as it arrives and checks it against the digest it was requested by.
+pub struct DigestVerifier {
+    expected: String,
+    hasher: Hasher,
+}
+
+impl DigestVerifier {
+    pub fn new(expected: &str) -> Result<Self> {
+        let hasher = match expected.split_once(':') {
+            Some(("sha256", _)) => Hasher::Sha256(Sha256::new()),
+            Some(("sha512", _)) => Hasher::Sha512(Sha512::new()),
+            _ => bail!("Unsupported digest algorithm in '{}'", expected),
+        };
+
+        Ok(DigestVerifier {
+            expected: expected.to_owned(),
+            hasher,
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.hasher {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finish(self) -> Result<()> {
+        let actual = match self.hasher {
+            Hasher::Sha256(hasher) => format!("sha256:{:x}", hasher.finalize()),
+            Hasher::Sha512(hasher) => format!("sha512:{:x}", hasher.finalize()),
+        };
+        if actual != self.expected {
+            return Err(PullError::DigestMismatch {
+                expected: self.expected,
+                actual,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that `data` is the content `expected` (a `sha256:...` or `sha512:...` digest) refers
+/// to.
+pub fn verify_digest(expected: &str, data: &[u8]) -> Result<()> {
+    let mut verifier = DigestVerifier::new(expected)?;
+    verifier.update(data);
+    verifier.finish()
+}
+
+/// The `sha256:...` digest addressing `data`.
+pub fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
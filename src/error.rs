@@ -0,0 +1,57 @@
+/// The ways a pull fails that callers may want to tell apart, anything else is `Other`. Deeper
+/// down errors stay `anyhow` and carry one of these as their cause, `From<anyhow::Error>` digs
+/// it back out.
+#[derive(Debug, thiserror::Error)]
+pub enum PullError {
+    #[error("Authentication with {registry} failed: {reason}")]
+    Auth { registry: String, reason: String },
+    #[error("No manifest {reference} in {repository}")]
+    ManifestNotFound {
+        repository: String,
+        reference: String,
+    },
+    #[error("No manifest found for {platform}, available platforms: {}", .available.join(", "))]
+    PlatformUnavailable {
+        platform: String,
+        available: Vec<String>,
+    },
+    #[error("Digest mismatch: expected {expected}, got {actual}")]
+    DigestMismatch { expected: String, actual: String },
+    #[error("Failed to unpack layer {digest}")]
+    Extraction {
+        digest: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for PullError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<PullError>() {
+            Ok(err) => err,
+            Err(err) => PullError::Other(err),
+        }
+    }
+}
+
+// So that `?` works in functions returning a `PullError` without turning everything into an
+// `anyhow::Error` by hand first
+impl From<std::io::Error> for PullError {
+    fn from(err: std::io::Error) -> Self {
+        PullError::Other(err.into())
+    }
+}
+
+impl From<reqwest::Error> for PullError {
+    fn from(err: reqwest::Error) -> Self {
+        PullError::Other(err.into())
+    }
+}
+
+impl From<serde_json::Error> for PullError {
+    fn from(err: serde_json::Error) -> Self {
+        PullError::Other(err.into())
+    }
+}
@@ -0,0 +1,368 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    cache::{self, BlobCache},
+    digest,
+    platform::Platform,
+    reference::{self, ImageReference, DEFAULT_REGISTRY},
+};
+
+/// What we know about a pulled image: which manifest the reference resolved to and the layer
+/// blobs that make it up, in the order they are unpacked.
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+pub struct ImageRecord {
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    /// Digest of the platform specific image manifest
+    pub digest: String,
+    pub platform: Platform,
+    /// Digest of the image config blob, missing in entries from before we kept it
+    #[serde(default)]
+    pub config: Option<String>,
+    pub layers: Vec<String>,
+}
+
+impl ImageRecord {
+    /// Every blob the image needs from the cache, its config as well as its layers.
+    fn blobs(&self) -> impl Iterator<Item = &String> {
+        self.config.iter().chain(&self.layers)
+    }
+
+    /// Whether `reference` names this image, by tag or by digest.
+    fn matches(&self, reference: &ImageReference) -> bool {
+        if self.registry != reference.registry || self.repository != reference.repository {
+            return false;
+        }
+
+        match &reference.digest {
+            Some(digest) => *digest == self.digest,
+            None => self.tag.as_deref() == Some(reference.reference()),
+        }
+    }
+
+    /// The repository the way Docker prints it, without the Docker Hub host and `library/`.
+    pub fn display_repository(&self) -> String {
+        match self.registry.as_str() {
+            DEFAULT_REGISTRY => self
+                .repository
+                .strip_prefix("library/")
+                .unwrap_or(&self.repository)
+                .to_owned(),
+            registry => format!("{}/{}", registry, self.repository),
+        }
+    }
+}
+
+/// Local record of pulled images, one JSON file per reference and platform. The files only
+/// point at layers, the blobs themselves live in the `BlobCache`.
+pub struct ImageStore {
+    dir: PathBuf,
+}
+
+impl ImageStore {
+    pub fn new(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create image store {}", dir.display()))?;
+
+        Ok(ImageStore {
+            dir: dir.to_owned(),
+        })
+    }
+
+    /// `~/.mydocker/images`, next to the layer cache.
+    pub fn default_dir() -> PathBuf {
+        cache::state_dir().join("images")
+    }
+
+    /// References contain `/` and `:`, so entries are named by a hash of what identifies them.
+    fn path(&self, reference: &ImageReference, platform: &Platform) -> PathBuf {
+        let key = format!(
+            "{}/{}@{} {}",
+            reference.registry,
+            reference.repository,
+            reference.reference(),
+            platform
+        );
+        let digest = digest::sha256_digest(key.as_bytes());
+        let name = digest.trim_start_matches("sha256:");
+        self.dir.join(format!("{}.json", name))
+    }
+
+    /// The image `reference` was last pulled as for `platform`, if any.
+    pub fn get(
+        &self,
+        reference: &ImageReference,
+        platform: &Platform,
+    ) -> Result<Option<ImageRecord>> {
+        let path = self.path(reference, platform);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        read_record(&path).map(Some)
+    }
+
+    pub fn save(
+        &self,
+        reference: &ImageReference,
+        requested_platform: &Platform,
+        record: &ImageRecord,
+    ) -> Result<()> {
+        let path = self.path(reference, requested_platform);
+
+        // Write next to the entry and rename so a crash never leaves half a record behind
+        let temp_file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        serde_json::to_writer_pretty(temp_file.as_file(), record)?;
+        temp_file
+            .persist(&path)
+            .with_context(|| format!("Failed to save {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// Every entry in the store with the file it lives in. Entries that can't be read are
+    /// returned as errors, so one broken file doesn't hide the rest.
+    pub fn list(&self) -> Result<Vec<(PathBuf, Result<ImageRecord>)>> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)
+            .with_context(|| format!("Failed to read image store {}", self.dir.display()))?
+        {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        Ok(paths
+            .into_iter()
+            .map(|path| {
+                let record = read_record(&path);
+                (path, record)
+            })
+            .collect())
+    }
+}
+
+fn read_record(path: &Path) -> Result<ImageRecord> {
+    let data = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_slice(&data)
+        .with_context(|| format!("Corrupt image store entry {}", path.display()))
+}
+
+/// The local config and layer blobs of `record`, or `None` if any of them has gone missing
+/// from the cache.
+pub fn cached_blobs(record: &ImageRecord, cache: &BlobCache) -> Option<(PathBuf, Vec<PathBuf>)> {
+    let config = cache.get(record.config.as_deref()?)?;
+    let layers = record
+        .layers
+        .iter()
+        .map(|digest| cache.get(digest))
+        .collect::<Option<_>>()?;
+
+    Some((config, layers))
+}
+
+/// Sizes in Docker's style, decimal units with up to three significant digits.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{}{}", bytes, UNITS[0]),
+        _ if size < 10.0 => format!("{:.2}{}", size, UNITS[unit]),
+        _ if size < 100.0 => format!("{:.1}{}", size, UNITS[unit]),
+        _ => format!("{:.0}{}", size, UNITS[unit]),
+    }
+}
+
+/// Prints the image store as a table, like `docker images`. The size is that of the compressed
+/// layers we have cached, so images sharing layers count them more than once.
+pub fn print_images(store: &ImageStore, cache: &BlobCache) -> Result<()> {
+    let mut rows = vec![[
+        "REPOSITORY".to_owned(),
+        "TAG".to_owned(),
+        "DIGEST".to_owned(),
+        "PLATFORM".to_owned(),
+        "SIZE".to_owned(),
+    ]];
+
+    for (_, record) in store.list()? {
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                tracing::warn!("{:#}", err);
+                continue;
+            }
+        };
+
+        let mut missing = 0;
+        let size: u64 = record
+            .layers
+            .iter()
+            .filter_map(|digest| match cache.get(digest) {
+                Some(path) => std::fs::metadata(path).ok(),
+                None => {
+                    missing += 1;
+                    None
+                }
+            })
+            .map(|metadata| metadata.len())
+            .sum();
+        if missing > 0 {
+            tracing::warn!(
+                "{}: {} of {} layers are missing from the cache",
+                record.display_repository(),
+                missing,
+                record.layers.len()
+            );
+        }
+
+        // Shortened like Docker's image IDs, the full digest is in the store
+        let hex = record
+            .digest
+            .split_once(':')
+            .map_or(record.digest.as_str(), |(_, hex)| hex);
+        rows.push([
+            record.display_repository(),
+            record.tag.clone().unwrap_or_else(|| "<none>".to_owned()),
+            hex.chars().take(12).collect(),
+            record.platform.to_string(),
+            format_size(size),
+        ]);
+    }
+
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in &rows {
+        let line: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("   ").trim_end());
+    }
+
+    Ok(())
+}
+
+/// Every blob some image in the store refers to. Fails if an entry can't be read, since its
+/// blobs could be anything.
+pub fn layers_in_use(store: &ImageStore) -> Result<HashSet<String>> {
+    let mut layers = HashSet::new();
+    for (_, record) in store.list()? {
+        let record = record.context("Can't tell which layers are in use")?;
+        layers.extend(record.blobs().cloned());
+    }
+
+    Ok(layers)
+}
+
+/// Names of the containers whose state directory still refers to the image `digest`.
+fn containers_using(digest: &str) -> Result<Vec<String>> {
+    let dir = cache::state_dir().join("containers");
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let image = std::fs::read_to_string(entry.path().join("image")).unwrap_or_default();
+        if image.trim() == digest {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+
+    Ok(names)
+}
+
+/// Removes the images `reference` names (one per platform it was pulled for), then every layer
+/// blob no remaining image refers to. Unless `force` is set, images that a container still
+/// uses are left alone.
+pub fn remove_image(
+    store: &ImageStore,
+    cache: &BlobCache,
+    image_name: &str,
+    force: bool,
+) -> Result<()> {
+    let reference = reference::parse_image_reference(image_name)?;
+    let entries = store.list()?;
+
+    let mut removed = Vec::new();
+    let mut remaining = Vec::new();
+    let mut unreadable = false;
+    for (path, record) in entries {
+        match record {
+            Ok(record) if record.matches(&reference) => removed.push((path, record)),
+            Ok(record) => remaining.push(record),
+            Err(err) => {
+                tracing::warn!("{:#}", err);
+                unreadable = true;
+            }
+        }
+    }
+
+    if removed.is_empty() {
+        bail!("No such image: {}", image_name);
+    }
+
+    if !force {
+        for (_, record) in &removed {
+            let containers = containers_using(&record.digest)?;
+            if !containers.is_empty() {
+                bail!(
+                    "Image {} is in use by {}, use -f to remove it anyway",
+                    record.digest,
+                    containers.join(", ")
+                );
+            }
+        }
+    }
+
+    for (path, record) in &removed {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+        println!(
+            "Untagged: {}:{}",
+            record.display_repository(),
+            record.tag.as_deref().unwrap_or("<none>")
+        );
+    }
+
+    // Without knowing what the broken entries point at, any layer could still be needed
+    if unreadable {
+        tracing::warn!("Keeping all layers since some image store entries can't be read");
+        return Ok(());
+    }
+
+    let in_use: HashSet<_> = remaining.iter().flat_map(ImageRecord::blobs).collect();
+    let mut deleted = HashSet::new();
+    for blob in removed.iter().flat_map(|(_, record)| record.blobs()) {
+        if !in_use.contains(blob) && deleted.insert(blob) && cache.remove(blob)? {
+            println!("Deleted: {}", blob);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,823 @@
+use anyhow::{bail, Context, Result};
+use flate2::bufread::GzDecoder;
+use std::{
+    collections::{HashSet, VecDeque},
+    ffi::{CString, OsStr, OsString},
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind, Read},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Component, Path, PathBuf},
+    sync::Once,
+};
+
+/// Marks the file of the same name without the prefix as deleted in a lower layer.
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Marks its directory as replacing, not merging with, the one in lower layers.
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+/// Largest single file we unpack. Real images stay far below, anything bigger is most likely
+/// a sparse file claiming a size it never had.
+const MAX_ENTRY_SIZE: u64 = 16 << 30;
+
+/// How many more bytes the image's layers may unpack to, shared by all of them.
+pub struct SizeBudget {
+    remaining: u64,
+}
+
+impl SizeBudget {
+    pub fn new(limit: u64) -> Self {
+        SizeBudget { remaining: limit }
+    }
+
+    fn take(&mut self, size: u64) -> Result<()> {
+        if size > MAX_ENTRY_SIZE {
+            bail!("it is {} bytes, more than the limit for one file", size);
+        }
+        self.remaining = self
+            .remaining
+            .checked_sub(size)
+            .context("the image unpacks to more than --max-unpacked-size")?;
+        Ok(())
+    }
+}
+
+/// How a layer's tar is compressed, going by its media type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Covers the Docker and OCI layer types, non-distributable ones included.
+    pub fn from_media_type(media_type: &str) -> Result<Self> {
+        match media_type {
+            "application/vnd.docker.image.rootfs.diff.tar.gzip"
+            | "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip"
+            | "application/vnd.oci.image.layer.v1.tar+gzip"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip" => {
+                Ok(Compression::Gzip)
+            }
+            "application/vnd.oci.image.layer.v1.tar+zstd"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd" => {
+                Ok(Compression::Zstd)
+            }
+            "application/vnd.oci.image.layer.v1.tar"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar" => Ok(Compression::None),
+            media_type => bail!("unsupported layer media type {}", media_type),
+        }
+    }
+
+    /// Tells from the magic number at the start of the blob, for layers we don't know the
+    /// media type of. Anything that isn't gzip or zstd is taken to be a plain tar.
+    fn sniff(reader: &mut impl BufRead) -> Result<Self> {
+        let magic = reader.fill_buf()?;
+        Ok(if magic.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        })
+    }
+
+    fn decoder<'a>(self, reader: impl BufRead + 'a) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(GzDecoder::new(reader)),
+            Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        })
+    }
+}
+
+/// Unpacks a layer on top of the layers already unpacked into `root`, applying its whiteouts
+/// the way overlayfs would so that files deleted in this layer don't reappear. Ownership and
+/// special mode bits are only kept when running as root. Without a `compression` it is
+/// detected from the blob itself. Entries that would climb out of `root` abort the unpack,
+/// they have no business in a layer.
+pub fn unpack_layer(
+    layer_path: &Path,
+    compression: Option<Compression>,
+    root: &Path,
+    budget: &mut SizeBudget,
+) -> Result<()> {
+    let file = File::open(layer_path)
+        .with_context(|| format!("Failed to open layer blob {}", layer_path.display()))?;
+    let mut reader = BufReader::new(file);
+    let compression = match compression {
+        Some(compression) => compression,
+        None => Compression::sniff(&mut reader)?,
+    };
+    let mut archive = tar::Archive::new(compression.decoder(reader)?);
+
+    // Images are made to run as their files' owners, with setuid binaries and file capabilities
+    // (`security.capability`, which `ping` relies on) and all, which only comes out right when
+    // unpacking as root. Anyone else can't chown or set most xattrs, so they get their own files
+    // rather than an error.
+    let as_root = unsafe { libc::geteuid() } == 0;
+    if !as_root {
+        static WARN_ONCE: Once = Once::new();
+        WARN_ONCE.call_once(|| {
+            tracing::warn!(
+                "Not running as root, unpacked files will be owned by the current user and lose \
+                 their extended attributes"
+            )
+        });
+    }
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(as_root);
+    archive.set_preserve_mtime(true);
+    // Applied after the owner, since a chown drops file capabilities
+    archive.set_unpack_xattrs(as_root);
+
+    // An opaque whiteout only hides the lower layers, never what this layer put there itself,
+    // whichever order the tar lists them in
+    let mut unpacked = HashSet::new();
+    let mut deferred_links = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let Some(relative) = normalize(&path) else {
+            bail!("Layer entry {} points outside the root", path.display());
+        };
+
+        match relative.file_name().and_then(OsStr::to_str) {
+            Some(OPAQUE_WHITEOUT) => {
+                // Followed all the way, listing a symlinked directory lists where it points
+                let dir = resolve_all_in_root(root, relative.parent().unwrap_or(Path::new("")))?;
+                clear_dir(&dir, &unpacked)?;
+            }
+            Some(name) if name.starts_with(WHITEOUT_PREFIX) => {
+                let hidden = relative.with_file_name(&name[WHITEOUT_PREFIX.len()..]);
+                remove_path(&resolve_in_root(root, &hidden)?)?;
+            }
+            _ => {
+                let dst = resolve_in_root(root, &relative)?;
+                if dst == root {
+                    continue;
+                }
+                budget
+                    .take(entry.size())
+                    .with_context(|| format!("Refusing to unpack {}", path.display()))?;
+                let missing_target = unpack_entry(&mut entry, root, &dst, as_root)
+                    .with_context(|| format!("Failed to unpack {}", path.display()))?;
+                if let Some(target) = missing_target {
+                    deferred_links.push((target, dst, path));
+                    continue;
+                }
+                unpacked.extend(dst.ancestors().map(Path::to_owned));
+            }
+        }
+    }
+
+    // Tars list a hardlink after the file it points at, but nothing makes them
+    for (target, dst, path) in deferred_links {
+        std::fs::hard_link(&target, &dst).with_context(|| {
+            format!(
+                "Failed to unpack {}, the file it links to isn't in the layer",
+                path.display()
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Unpacks a single entry to `dst`, which has already been resolved inside `root`. Hardlinks
+/// and symlinks are made by hand: tar would resolve hardlinks against the host filesystem, and
+/// absolute symlinks only make sense once we chroot into `root`. A hardlink to a file that
+/// isn't there yet is left to the caller, returning where its target will be.
+fn unpack_entry<R: std::io::Read>(
+    entry: &mut tar::Entry<R>,
+    root: &Path,
+    dst: &Path,
+    as_root: bool,
+) -> Result<Option<PathBuf>> {
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // Whatever a lower layer had at this path is replaced, except that directories merge
+    let entry_type = entry.header().entry_type();
+    match std::fs::symlink_metadata(dst) {
+        Ok(existing) if existing.is_dir() && entry_type.is_dir() => {}
+        Ok(_) => remove_path(dst)?,
+        Err(_) => {}
+    }
+
+    match entry_type {
+        tar::EntryType::Link => {
+            let target = link_target(entry)?;
+            let Some(target) = normalize(&target) else {
+                bail!("Hardlink target {} is outside the root", target.display());
+            };
+            let target = resolve_in_root(root, &target)?;
+            if std::fs::symlink_metadata(&target).is_err() {
+                return Ok(Some(target));
+            }
+            std::fs::hard_link(target, dst)?;
+        }
+        tar::EntryType::Symlink => {
+            // Stored as is, the target is resolved at run time against the container's root
+            std::os::unix::fs::symlink(link_target(entry)?, dst)?;
+            if as_root {
+                let header = entry.header();
+                std::os::unix::fs::lchown(
+                    dst,
+                    Some(header.uid()? as u32),
+                    Some(header.gid()? as u32),
+                )?;
+            }
+        }
+        // Only root may create device nodes, which the container can do without anyway
+        tar::EntryType::Char | tar::EntryType::Block if !as_root => {
+            tracing::debug!("Skipping device node {}", dst.display());
+        }
+        // tar would unpack these as empty regular files
+        tar::EntryType::Char | tar::EntryType::Block | tar::EntryType::Fifo => {
+            make_node(entry.header(), dst, as_root)?;
+        }
+        _ => {
+            entry.unpack(dst)?;
+        }
+    }
+
+    Ok(None)
+}
+
+fn make_node(header: &tar::Header, dst: &Path, as_root: bool) -> Result<()> {
+    let kind = match header.entry_type() {
+        tar::EntryType::Char => libc::S_IFCHR,
+        tar::EntryType::Block => libc::S_IFBLK,
+        _ => libc::S_IFIFO,
+    };
+    // Only devices have their numbers filled in
+    let device = match kind {
+        libc::S_IFIFO => 0,
+        _ => libc::makedev(
+            header.device_major()?.unwrap_or(0),
+            header.device_minor()?.unwrap_or(0),
+        ),
+    };
+    let mode = header.mode()? & 0o7777;
+
+    let path = CString::new(dst.as_os_str().as_bytes())?;
+    if unsafe { libc::mknod(path.as_ptr(), kind | mode, device) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if as_root {
+        std::os::unix::fs::lchown(dst, Some(header.uid()? as u32), Some(header.gid()? as u32))?;
+    }
+    // mknod applies the umask, and a chown clears setuid bits
+    std::fs::set_permissions(dst, std::fs::Permissions::from_mode(mode))?;
+
+    Ok(())
+}
+
+fn link_target<R: std::io::Read>(entry: &tar::Entry<R>) -> Result<PathBuf> {
+    match entry.link_name()? {
+        Some(target) if !target.as_os_str().is_empty() => Ok(target.into_owned()),
+        _ => bail!("Link without a target"),
+    }
+}
+
+/// A path component that outlives the path it came from.
+enum Part {
+    Parent,
+    Normal(OsString),
+}
+
+fn parts(path: &Path) -> VecDeque<Part> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(Part::Normal(part.to_owned())),
+            Component::ParentDir => Some(Part::Parent),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => None,
+        })
+        .collect()
+}
+
+/// Maximum number of symlinks followed while resolving one path, like the kernel's limit.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Resolves `path` the way it would be seen from inside `root` after a chroot: symlinks in
+/// its directories are followed, with absolute targets and `..` never leaving `root`. The
+/// last component isn't followed, so that links themselves can be replaced or removed.
+pub fn resolve_in_root(root: &Path, path: &Path) -> Result<PathBuf> {
+    resolve(root, path, false)
+}
+
+/// Like `resolve_in_root`, but follows a symlink in the last component as well. For paths
+/// handed to the host kernel, which would follow it out of the root.
+pub fn resolve_all_in_root(root: &Path, path: &Path) -> Result<PathBuf> {
+    resolve(root, path, true)
+}
+
+fn resolve(root: &Path, path: &Path, follow_last: bool) -> Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    let mut pending = parts(path);
+    let mut hops = 0;
+
+    while let Some(part) = pending.pop_front() {
+        let part = match part {
+            Part::Normal(part) => part,
+            Part::Parent => {
+                resolved.pop();
+                continue;
+            }
+        };
+
+        let candidate = resolved.join(part);
+        let host_path = root.join(&candidate);
+        let is_symlink = std::fs::symlink_metadata(&host_path)
+            .is_ok_and(|metadata| metadata.file_type().is_symlink());
+        if !is_symlink || (pending.is_empty() && !follow_last) {
+            resolved = candidate;
+            continue;
+        }
+
+        hops += 1;
+        if hops > MAX_SYMLINK_HOPS {
+            bail!("Too many levels of symbolic links in {}", path.display());
+        }
+        let target = std::fs::read_link(&host_path)?;
+        if target.has_root() {
+            resolved = PathBuf::new();
+        }
+        let mut followed = parts(&target);
+        followed.extend(pending);
+        pending = followed;
+    }
+
+    Ok(root.join(resolved))
+}
+
+/// The entry's path relative to the root, or `None` if it would climb out of it.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// Deletes everything in `dir` that came from lower layers.
+fn clear_dir(dir: &Path, keep: &HashSet<PathBuf>) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+        if !keep.contains(&path) {
+            remove_path(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes a file, symlink or whole directory tree. Whiting out something that doesn't exist
+/// is fine.
+fn remove_path(path: &Path) -> Result<()> {
+    let result = match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("Failed to remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The layers unpacked into a root that outlives a pull, in the order they were applied. Kept
+/// in a file outside the root, so that pulling into the same root again, such as after a pull
+/// that broke off, only unpacks the layers it doesn't hold yet.
+pub struct AppliedLayers {
+    path: PathBuf,
+    digests: Vec<String>,
+}
+
+impl AppliedLayers {
+    /// Reads the record at `path` and works out how many of `layers`, the digests of an image's
+    /// layers in order, `root` already holds. Layers only build on the ones below them, so that
+    /// is the part of the record `layers` starts with. If the record isn't such a start, say
+    /// because the root holds another image, `root` is emptied and nothing can be skipped.
+    pub fn open(path: &Path, root: &Path, layers: &[String]) -> Result<(Self, usize)> {
+        let digests: Vec<String> = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+
+        let mut applied = AppliedLayers {
+            path: path.to_owned(),
+            digests,
+        };
+        if !applied.digests.is_empty() && layers.starts_with(&applied.digests) {
+            let skip = applied.digests.len();
+            return Ok((applied, skip));
+        }
+
+        // Whatever is there, we don't know what it is
+        for entry in std::fs::read_dir(root)? {
+            remove_path(&entry?.path())?;
+        }
+        applied.digests.clear();
+        applied.write()?;
+        Ok((applied, 0))
+    }
+
+    /// Records that `digest` was unpacked on top of the layers before it.
+    pub fn push(&mut self, digest: &str) -> Result<()> {
+        self.digests.push(digest.to_owned());
+        self.write()
+    }
+
+    /// Replaced as a whole, so that a pull that dies halfway never leaves half a record.
+    fn write(&self) -> Result<()> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let temp_file = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer(temp_file.as_file(), &self.digests)?;
+        temp_file
+            .persist(&self.path)
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    /// A layer written entry by entry. Paths and link targets go into the header as they are,
+    /// `tar::Builder` would refuse the hostile ones.
+    struct Layer(tar::Builder<Vec<u8>>);
+
+    impl Layer {
+        fn new() -> Self {
+            Layer(tar::Builder::new(Vec::new()))
+        }
+
+        fn header(path: &str, entry_type: tar::EntryType, mode: u32) -> tar::Header {
+            let mut header = tar::Header::new_gnu();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(entry_type);
+            header.set_mode(mode);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header
+        }
+
+        fn append(mut self, mut header: tar::Header, contents: &str) -> Self {
+            header.set_size(contents.len() as u64);
+            header.set_cksum();
+            self.0.append(&header, contents.as_bytes()).unwrap();
+            self
+        }
+
+        /// `data` is a link's target, or else the contents.
+        fn entry(self, path: &str, entry_type: tar::EntryType, mode: u32, data: &str) -> Self {
+            let mut header = Self::header(path, entry_type, mode);
+            match entry_type {
+                tar::EntryType::Link | tar::EntryType::Symlink => {
+                    header.as_old_mut().linkname[..data.len()].copy_from_slice(data.as_bytes());
+                    self.append(header, "")
+                }
+                _ => self.append(header, data),
+            }
+        }
+
+        fn char_device(self, path: &str, major: u32, minor: u32) -> Self {
+            let mut header = Self::header(path, tar::EntryType::Char, 0o666);
+            header.set_device_major(major).unwrap();
+            header.set_device_minor(minor).unwrap();
+            self.append(header, "")
+        }
+
+        fn file(self, path: &str, contents: &str) -> Self {
+            self.entry(path, tar::EntryType::Regular, 0o644, contents)
+        }
+
+        /// Unpacks the layer into `root` without a size limit.
+        fn unpack(self, root: &Path) -> Result<()> {
+            self.unpack_within(root, &mut SizeBudget::new(u64::MAX))
+        }
+
+        fn unpack_within(self, root: &Path, budget: &mut SizeBudget) -> Result<()> {
+            let blob = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(blob.path(), self.0.into_inner().unwrap()).unwrap();
+            unpack_layer(blob.path(), Some(Compression::None), root, budget)
+        }
+    }
+
+    #[test]
+    fn a_whiteout_deletes_the_file_from_the_layer_below() {
+        let root = tempfile::tempdir().unwrap();
+        Layer::new()
+            .file("etc/keep", "kept")
+            .file("etc/gone", "deleted")
+            .unpack(root.path())
+            .unwrap();
+        Layer::new()
+            .file("etc/.wh.gone", "")
+            .unpack(root.path())
+            .unwrap();
+
+        assert!(!root.path().join("etc/gone").exists());
+        assert!(!root.path().join("etc/.wh.gone").exists());
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("etc/keep")).unwrap(),
+            "kept"
+        );
+    }
+
+    #[test]
+    fn an_opaque_whiteout_hides_only_the_lower_layers() {
+        let root = tempfile::tempdir().unwrap();
+        Layer::new()
+            .file("app/old", "")
+            .file("app/lib/old", "")
+            .file("other", "")
+            .unpack(root.path())
+            .unwrap();
+        // Files of its own on both sides of the marker
+        Layer::new()
+            .file("app/before", "")
+            .file("app/.wh..wh..opq", "")
+            .file("app/after", "")
+            .unpack(root.path())
+            .unwrap();
+
+        let mut names: Vec<_> = std::fs::read_dir(root.path().join("app"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["after", "before"]);
+        assert!(root.path().join("other").exists());
+    }
+
+    #[test]
+    fn setuid_files_keep_their_mode() {
+        let root = tempfile::tempdir().unwrap();
+        Layer::new()
+            .entry("bin/su", tar::EntryType::Regular, 0o4755, "")
+            .unpack(root.path())
+            .unwrap();
+
+        let mode = std::fs::metadata(root.path().join("bin/su"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o4755);
+    }
+
+    #[test]
+    fn hardlinks_share_the_file_and_symlinks_stay_relative() {
+        let root = tempfile::tempdir().unwrap();
+        Layer::new()
+            .file("bin/busybox", "busybox")
+            .entry("bin/sh", tar::EntryType::Link, 0o755, "bin/busybox")
+            .file("usr/lib/libc.so.6", "libc")
+            .unpack(root.path())
+            .unwrap();
+        // Links in a later layer, to files of the one below
+        Layer::new()
+            .entry("lib", tar::EntryType::Symlink, 0o777, "usr/lib")
+            .entry(
+                "usr/lib/libc.so",
+                tar::EntryType::Symlink,
+                0o777,
+                "../lib/libc.so.6",
+            )
+            .entry("bin/ls", tar::EntryType::Link, 0o755, "bin/busybox")
+            .unpack(root.path())
+            .unwrap();
+
+        let inode = |path: &str| std::fs::metadata(root.path().join(path)).unwrap().ino();
+        assert_eq!(inode("bin/sh"), inode("bin/busybox"));
+        assert_eq!(inode("bin/ls"), inode("bin/busybox"));
+        assert_eq!(
+            std::fs::read_link(root.path().join("usr/lib/libc.so")).unwrap(),
+            Path::new("../lib/libc.so.6")
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("lib/libc.so")).unwrap(),
+            "libc"
+        );
+    }
+
+    #[test]
+    fn a_hardlink_may_come_before_its_target() {
+        let root = tempfile::tempdir().unwrap();
+        Layer::new()
+            .entry("bin/sh", tar::EntryType::Link, 0o755, "bin/busybox")
+            .file("bin/busybox", "busybox")
+            .unpack(root.path())
+            .unwrap();
+
+        let inode = |path: &str| std::fs::metadata(root.path().join(path)).unwrap().ino();
+        assert_eq!(inode("bin/sh"), inode("bin/busybox"));
+
+        let err = Layer::new()
+            .entry("bin/ls", tar::EntryType::Link, 0o755, "bin/nowhere")
+            .unpack(root.path())
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("isn't in the layer"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn devices_are_made_as_root_and_skipped_otherwise() {
+        let root = tempfile::tempdir().unwrap();
+        Layer::new()
+            .char_device("dev/null", 1, 3)
+            .entry("run/fifo", tar::EntryType::Fifo, 0o600, "")
+            .unpack(root.path())
+            .unwrap();
+
+        let fifo = std::fs::metadata(root.path().join("run/fifo")).unwrap();
+        assert!(fifo.file_type().is_fifo());
+        assert_eq!(fifo.permissions().mode() & 0o7777, 0o600);
+        match unsafe { libc::geteuid() } {
+            0 => {
+                let null = std::fs::metadata(root.path().join("dev/null")).unwrap();
+                assert!(std::os::unix::fs::FileTypeExt::is_char_device(
+                    &null.file_type()
+                ));
+                assert_eq!(null.rdev(), libc::makedev(1, 3));
+            }
+            _ => assert!(!root.path().join("dev/null").exists()),
+        }
+    }
+
+    #[test]
+    fn entries_climbing_out_of_the_root_abort_the_unpack() {
+        let root = tempfile::tempdir().unwrap();
+        let escape = |layer: Layer| {
+            let err = layer.unpack(root.path()).unwrap_err();
+            assert!(
+                format!("{:#}", err).contains("outside the root"),
+                "{:#}",
+                err
+            );
+        };
+        escape(Layer::new().file("../../etc/passwd", "root::0:0::/:/bin/sh"));
+        escape(Layer::new().file("usr/../../passwd", ""));
+        escape(Layer::new().entry("passwd", tar::EntryType::Link, 0o644, "../../etc/passwd"));
+    }
+
+    #[test]
+    fn absolute_symlinks_are_followed_inside_the_root() {
+        let (root, outside) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let target = outside.path().to_str().unwrap();
+        Layer::new()
+            .entry("data", tar::EntryType::Symlink, 0o777, target)
+            .unpack(root.path())
+            .unwrap();
+        Layer::new()
+            .file("data/file", "")
+            .unpack(root.path())
+            .unwrap();
+
+        assert!(!outside.path().join("file").exists());
+        let inside = root.path().join(outside.path().strip_prefix("/").unwrap());
+        assert!(inside.join("file").exists());
+        // Left as it was, for the container to resolve against its own root
+        assert_eq!(
+            std::fs::read_link(root.path().join("data")).unwrap(),
+            outside.path()
+        );
+    }
+
+    #[test]
+    fn layers_stop_at_the_size_budget() {
+        let root = tempfile::tempdir().unwrap();
+        let mut budget = SizeBudget::new(10);
+        Layer::new()
+            .file("a", "123456")
+            .unpack_within(root.path(), &mut budget)
+            .unwrap();
+
+        // The budget is the image's, not each layer's
+        let err = Layer::new()
+            .file("b", "123456")
+            .unpack_within(root.path(), &mut budget)
+            .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("--max-unpacked-size"),
+            "{:#}",
+            err
+        );
+        assert!(!root.path().join("b").exists());
+    }
+
+    #[test]
+    fn writes_through_a_symlink_to_the_host_root_stay_in_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        let name = format!("escaped-{}", std::process::id());
+        Layer::new()
+            .entry("host", tar::EntryType::Symlink, 0o777, "/")
+            .entry("up", tar::EntryType::Symlink, 0o777, "../../../..")
+            .unpack(root.path())
+            .unwrap();
+        Layer::new()
+            .file(&format!("host/tmp/{}", name), "")
+            .file(&format!("up/tmp/{}-up", name), "")
+            .unpack(root.path())
+            .unwrap();
+
+        assert!(!Path::new("/tmp").join(&name).exists());
+        assert!(!Path::new("/tmp").join(format!("{}-up", name)).exists());
+        assert!(root.path().join("tmp").join(&name).exists());
+        assert!(root
+            .path()
+            .join("tmp")
+            .join(format!("{}-up", name))
+            .exists());
+    }
+
+    #[test]
+    fn an_opaque_whiteout_in_a_symlink_out_of_the_root_stays_in_the_root() {
+        let (root, outside) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        std::fs::write(outside.path().join("precious"), "").unwrap();
+        Layer::new()
+            .entry(
+                "app",
+                tar::EntryType::Symlink,
+                0o777,
+                outside.path().to_str().unwrap(),
+            )
+            .entry("up", tar::EntryType::Symlink, 0o777, "../../../..")
+            .unpack(root.path())
+            .unwrap();
+        Layer::new()
+            .file("app/.wh..wh..opq", "")
+            .file("up/.wh..wh..opq", "")
+            .unpack(root.path())
+            .unwrap();
+
+        assert!(outside.path().join("precious").exists());
+    }
+
+    fn digests(digests: &[&str]) -> Vec<String> {
+        digests.iter().map(|digest| digest.to_string()).collect()
+    }
+
+    #[test]
+    fn applied_layers_skip_the_start_of_the_image_the_root_holds() {
+        let (dir, root) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let record = dir.path().join("layers.json");
+        std::fs::write(root.path().join("leftover"), "").unwrap();
+
+        // Nothing recorded, so nothing in the root can be trusted
+        let (mut applied, skip) =
+            AppliedLayers::open(&record, root.path(), &digests(&["a", "b"])).unwrap();
+        assert_eq!(skip, 0);
+        assert!(!root.path().join("leftover").exists());
+        applied.push("a").unwrap();
+        std::fs::write(root.path().join("a"), "").unwrap();
+
+        let (_, skip) = AppliedLayers::open(&record, root.path(), &digests(&["a", "b"])).unwrap();
+        assert_eq!(skip, 1);
+        assert!(root.path().join("a").exists());
+        let (_, skip) = AppliedLayers::open(&record, root.path(), &digests(&["a"])).unwrap();
+        assert_eq!(skip, 1);
+    }
+
+    #[test]
+    fn applied_layers_of_another_image_empty_the_root() {
+        let (dir, root) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let record = dir.path().join("layers.json");
+        let (mut applied, _) = AppliedLayers::open(&record, root.path(), &[]).unwrap();
+        applied.push("a").unwrap();
+        applied.push("c").unwrap();
+        std::fs::create_dir(root.path().join("c")).unwrap();
+
+        // The same first layer isn't enough, `c` changed what `b` would go on top of
+        let (_, skip) = AppliedLayers::open(&record, root.path(), &digests(&["a", "b"])).unwrap();
+        assert_eq!(skip, 0);
+        assert_eq!(std::fs::read_dir(root.path()).unwrap().count(), 0);
+        let (_, skip) = AppliedLayers::open(&record, root.path(), &digests(&["a", "b"])).unwrap();
+        assert_eq!(skip, 0);
+    }
+}
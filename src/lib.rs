@@ -0,0 +1,26 @@
+//! Pulling and unpacking container images, the part of mydocker that is useful on its own.
+//! [`pull_image`] does it all: resolving the reference, auth, picking the platform's manifest,
+//! downloading layers through the local cache and unpacking them.
+
+mod auth;
+pub mod cache;
+pub mod config;
+mod credentials;
+pub mod digest;
+pub mod error;
+mod http;
+pub mod images;
+pub mod layer;
+pub mod platform;
+mod progress;
+pub mod pull;
+pub mod reference;
+mod registry;
+mod retry;
+#[cfg(test)]
+mod testing;
+mod token_cache;
+
+pub use config::ImageConfig;
+pub use error::PullError;
+pub use pull::{inspect_image, pull_image, ImageInfo, PullOptions, PulledImage};
@@ -1,207 +1,311 @@
+mod cgroups;
+mod cli;
+mod containers;
+mod mounts;
+mod network;
+mod rootfs;
+mod signals;
+mod user;
+
 use anyhow::{Context, Result};
-use flate2::bufread::GzDecoder;
-use reqwest::header::ACCEPT;
+use clap::Parser;
 use std::{
-    os::unix::fs,
-    path::{Path, PathBuf},
-    process::Stdio,
+    os::unix::{
+        ffi::OsStrExt,
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::Path,
+    process::{ExitStatus, Stdio},
+    time::Duration,
 };
 
-#[derive(serde::Deserialize, Debug)]
-struct AuthResp {
-    token: String,
-}
-
-#[derive(serde::Deserialize, Debug)]
-struct DistributionManifestResponse {
-    manifests: Vec<DistributionManifest>,
-}
+use docker_starter_rust::{cache, config, digest, images, inspect_image, pull_image};
 
-#[derive(serde::Deserialize, Debug)]
-struct DistributionManifest {
-    digest: String,
-    platform: Platform,
+/// A Docker style ID for the container, its default hostname. The temp dir's name is random
+/// already, hashing it only makes it look the part.
+fn container_id(temp_dir_path: &Path) -> String {
+    let digest = digest::sha256_digest(temp_dir_path.as_os_str().as_bytes());
+    digest["sha256:".len()..][..12].to_owned()
 }
 
-#[derive(serde::Deserialize, Debug)]
-struct Platform {
-    architecture: String,
-}
+fn set_hostname(hostname: &str) -> Result<()> {
+    if unsafe { libc::sethostname(hostname.as_ptr().cast(), hostname.len()) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to set hostname '{}'", hostname));
+    }
 
-#[derive(serde::Deserialize, Debug)]
-struct ImageManifestResponse {
-    layers: Vec<Layer>,
+    Ok(())
 }
 
-#[derive(serde::Deserialize, Debug)]
-struct Layer {
-    digest: String,
+/// Mirrors the shell convention: the child's own code, or 128 + signal if it was killed.
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
 }
 
-fn create_temp_dir() -> Result<PathBuf> {
-    // Create temp dir
-    let temp_dir = tempfile::tempdir()?;
-    let temp_dir_path = temp_dir.into_path();
+async fn run(mut args: cli::RunArgs) -> Result<()> {
+    if args.print_config {
+        let info = inspect_image(&args.image, &args.pull).await?;
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
 
-    // Because of some weirdness with chroot, we need to create the dev/null file
-    std::fs::create_dir_all(temp_dir_path.join("dev"))?;
-    std::fs::File::create(temp_dir_path.join("dev/null"))?;
+    // Read up front, so that a broken file fails before anything is pulled
+    let mut env_file_entries = Vec::new();
+    for path in &args.env_files {
+        env_file_entries.extend(config::read_env_file(path)?);
+    }
 
-    Ok(temp_dir_path)
-}
+    args.pull.prefer_local = true;
+    let named = args
+        .name
+        .as_deref()
+        .map(containers::NamedContainer::lock)
+        .transpose()?;
+    let (root, config) = match &named {
+        Some(container) => match container.config()? {
+            Some(config) => {
+                tracing::info!("Reusing the root of container {}", container.name());
+                (rootfs::RootDir::open(container.root(), !args.rm)?, config)
+            }
+            None => {
+                container.reset()?;
+                args.pull.applied_layers = Some(container.applied_layers());
+                let root = rootfs::RootDir::open(container.root(), !args.rm)?;
+                let image = pull_image(&args.image, root.path(), &args.pull).await?;
+                container.record(&image)?;
+                (root, image.config)
+            }
+        },
+        None => {
+            let root = rootfs::RootDir::create()?;
+            let image = pull_image(&args.image, root.path(), &args.pull).await?;
+            (root, image.config)
+        }
+    };
+    let temp_dir_path = root.path();
+    let command_line = config.command_line(args.entrypoint.as_deref(), &args.command);
+    let (command, command_args) = command_line
+        .split_first()
+        .context("No command given and the image doesn't define one")?;
+
+    // New mount and PID namespaces, for the container's own /proc, a UTS namespace for its
+    // own hostname and unless asked otherwise a network namespace. The mount namespace is only
+    // this thread's, so everything from here on, cleaning up the root included, has to stay on
+    // this thread: no awaiting.
+    //
+    // We stay outside the root ourselves, only the container's process chroots into it, so
+    // that we can still remove it once the container exits.
+    let mut flags = libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWUTS;
+    if args.network == cli::Network::None {
+        flags |= libc::CLONE_NEWNET;
+    }
+    if unsafe { libc::unshare(flags) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to create namespaces");
+    }
+    if args.network == cli::Network::None {
+        network::loopback_up()?;
+    }
+    mounts::make_private().context("Failed to make mounts private")?;
+    if args.read_only {
+        mounts::bind_root(temp_dir_path)?;
+    }
+    set_hostname(
+        &args
+            .hostname
+            .clone()
+            .unwrap_or_else(|| container_id(temp_dir_path)),
+    )?;
+    mounts::populate_dev(temp_dir_path)?;
+    mounts::mount_all(temp_dir_path, &args.volumes, &args.tmpfs)?;
+    // Last, so that no other mount hides them. The image's /proc or /sys may be a symlink,
+    // which must not lead us out of the root.
+    std::fs::create_dir_all(docker_starter_rust::layer::resolve_all_in_root(
+        temp_dir_path,
+        Path::new("/proc"),
+    )?)?;
+    // Not every setup lets us mount sysfs, and few images need it
+    let sysfs = mounts::mount_sysfs(temp_dir_path);
+    if let Err(err) = &sysfs {
+        tracing::warn!("{:#}", err);
+    }
 
-fn chroot_to_temp_dir(temp_dir_path: &Path) -> Result<()> {
-    fs::chroot(temp_dir_path)?;
-    std::env::set_current_dir("/")?;
+    // Everything up to here needs root, only the command itself runs as the image's user
+    let user = match args.user.as_deref().or(config.user()) {
+        Some(spec) => Some(user::resolve(temp_dir_path, spec)?),
+        None => None,
+    };
+    let workdir = args.workdir.as_deref().unwrap_or(config.working_dir());
+    let (entry, failures) = rootfs::Entry::new(temp_dir_path, workdir, user)?;
+
+    let limits = cgroups::Limits {
+        memory: args.memory,
+        cpus: args.cpus,
+    };
+    let cgroup = match limits.is_empty() {
+        true => None,
+        false => Some(cgroups::Cgroup::create(
+            &container_id(temp_dir_path),
+            &limits,
+        )?),
+    };
+    if let (Ok(()), Some(cgroup)) = (&sysfs, &cgroup) {
+        if let Err(err) = mounts::bind_cgroup(temp_dir_path, cgroup.path()) {
+            tracing::warn!("{:#}", err);
+        }
+    }
+    if args.read_only {
+        // The container can't create a missing working directory itself anymore
+        std::fs::create_dir_all(docker_starter_rust::layer::resolve_all_in_root(
+            temp_dir_path,
+            &Path::new("/").join(workdir),
+        )?)?;
+        mounts::remount_read_only(temp_dir_path)?;
+    }
 
-    Ok(())
+    // Run the command
+    let signals = signals::Forwarder::install()?;
+    let mut command_builder = std::process::Command::new(command);
+    // Joined before exec, so the limits hold from the command's first instruction on
+    if let Some(cgroup) = &cgroup {
+        let procs = cgroup.procs()?;
+        unsafe { command_builder.pre_exec(move || cgroups::join(&procs)) };
+    }
+    unsafe { command_builder.pre_exec(move || entry.enter()) };
+    let spawned = command_builder
+        .args(command_args)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .env_clear()
+        .envs(config.env())
+        .envs(
+            env_file_entries
+                .iter()
+                .filter_map(|entry| config::parse_env(entry)),
+        )
+        .envs(args.env.iter().filter_map(|entry| config::parse_env(entry)))
+        .spawn();
+    // Holds our copy of the entry, the failures are only complete once it's gone
+    drop(command_builder);
+    let mut failed = None;
+    for (step, err) in failures
+        .read()
+        .context("Failed to hear back from the container")?
+    {
+        match step {
+            rootfs::Step::MountProc => tracing::warn!("Failed to mount /proc: {}", err),
+            step => failed = Some(step),
+        }
+    }
+    let mut child = spawned.with_context(|| {
+        let workdir = Path::new("/").join(workdir);
+        match failed {
+            Some(rootfs::Step::CreateWorkdir) => {
+                format!("Failed to create working directory {}", workdir.display())
+            }
+            Some(rootfs::Step::EnterWorkdir) => {
+                format!(
+                    "Failed to change into working directory {}",
+                    workdir.display()
+                )
+            }
+            _ => format!("Failed to run '{}'", command),
+        }
+    })?;
+    signals.start(child.id(), Duration::from_secs(args.stop_timeout));
+    let status = child
+        .wait()
+        .with_context(|| format!("Failed waiting for '{}'", command))?;
+
+    // Exiting skips destructors. The root goes first, unmounting the cgroup it shows.
+    match args.keep_root {
+        true => eprintln!("Keeping the container's root {}", root.keep().display()),
+        false => drop(root),
+    }
+    drop(cgroup);
+    if let (true, Some(container)) = (args.rm, named) {
+        if let Err(err) = container.remove() {
+            tracing::warn!("{:#}", err);
+        }
+    }
+    std::process::exit(exit_code(status))
 }
 
-async fn get_auth_token(image: &str) -> Result<String, anyhow::Error> {
-    let auth_res = reqwest::get(format!(
-        "https://auth.docker.io/token?service=registry.docker.io&scope=repository:library/{}:pull",
-        image
-    ))
-    .await?
-    .json::<AuthResp>()
-    .await?;
+async fn pull(args: cli::PullArgs) -> Result<()> {
+    let root = rootfs::RootDir::create()?;
+    pull_image(&args.image, root.path(), &args.pull).await?;
+    println!("{}", root.keep().display());
 
-    Ok(auth_res.token)
+    Ok(())
 }
 
-async fn get_image_digest(
-    client: &reqwest::Client,
-    image: &str,
-    tag: &str,
-    token: &str,
-    platform_architecture: &str,
-) -> Result<String, anyhow::Error> {
-    let manifest: DistributionManifestResponse = client
-        .get(format!(
-            "https://registry.hub.docker.com/v2/library/{image}/manifests/{tag}",
-            image = image,
-            tag = tag
-        ))
-        .header(
-            ACCEPT,
-            "application/vnd.docker.distribution.manifest.list.v2+json",
-        )
-        .bearer_auth(token)
-        .send()
-        .await?
-        .json()
-        .await?;
-    let image_digest = &manifest
-        .manifests
-        .iter()
-        .find(|m| m.platform.architecture == platform_architecture)
-        .context("No manifest found for arm64")?
-        .digest;
-
-    Ok(image_digest.to_owned())
+fn list_images(args: cli::ImagesArgs) -> Result<()> {
+    let cache = cache::BlobCache::open(args.cache_dir.as_deref())?;
+    let store = images::ImageStore::new(&images::ImageStore::default_dir())?;
+    images::print_images(&store, &cache)
 }
 
-async fn get_image_layers(
-    client: &reqwest::Client,
-    image: &str,
-    image_digest: &str,
-    token: &str,
-) -> Result<Vec<Layer>, anyhow::Error> {
-    let image_manifest: ImageManifestResponse = client
-        .get(format!(
-            "https://registry.hub.docker.com/v2/library/{image}/manifests/{digest}",
-            image = image,
-            digest = image_digest
-        ))
-        .header(ACCEPT, "application/vnd.oci.image.manifest.v1+json")
-        .bearer_auth(token)
-        .send()
-        .await?
-        .json()
-        .await?;
-
-    Ok(image_manifest.layers)
+fn remove_image(args: cli::RmiArgs) -> Result<()> {
+    let cache = cache::BlobCache::open(args.cache_dir.as_deref())?;
+    let store = images::ImageStore::new(&images::ImageStore::default_dir())?;
+    images::remove_image(&store, &cache, &args.image, args.force)
 }
 
-async fn download_layers(
-    client: &reqwest::Client,
-    token: &str,
-    image: &str,
-    layers: Vec<Layer>,
-    temp_dir_path: &Path,
-) -> Result<(), anyhow::Error> {
-    for layer in layers {
-        let layer_data = client
-            .get(format!(
-                "https://registry.hub.docker.com/v2/library/{image}/blobs/{digest}",
-                image = image,
-                digest = layer.digest
-            ))
-            .bearer_auth(token)
-            .send()
-            .await?
-            .bytes()
-            .await?;
-
-        let gzip_decoder = GzDecoder::new(layer_data.as_ref());
-        tar::Archive::new(gzip_decoder).unpack(temp_dir_path)?;
-    }
+fn prune(args: cli::PruneArgs) -> Result<()> {
+    let cache = cache::BlobCache::open(args.cache_dir.as_deref())?;
+    let store = images::ImageStore::new(&images::ImageStore::default_dir())?;
+    let report = cache.prune(
+        &images::layers_in_use(&store)?,
+        args.cache_max_size,
+        args.dry_run,
+    )?;
+
+    let verb = match args.dry_run {
+        true => "Would reclaim",
+        false => "Reclaimed",
+    };
+    println!(
+        "{} {} blobs, {}",
+        verb,
+        report.blobs,
+        indicatif::HumanBytes(report.bytes)
+    );
 
     Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args: Vec<_> = std::env::args().collect();
-    let image_name = &args[2];
-    let command = &args[3];
-    let command_args = &args[4..];
-
-    let temp_dir_path = create_temp_dir()?;
-
-    let mut split = image_name.split(':');
-    let image = split.next().unwrap();
-    let tag = split.next().unwrap_or("latest");
-
-    // Get docker registry token
-    let token = get_auth_token(image).await?;
-
-    // Get the manifest for this image distribution
-    let client = reqwest::Client::new();
-
-    // Get the image digest (id) for arm64
-    let image_digest = get_image_digest(&client, image, tag, &token, "arm64").await?;
-
-    // Download layers from docker registry
-    let image_layers = get_image_layers(&client, image, &image_digest, &token).await?;
-    // Download each layer and unpack it to the temp dir
-    download_layers(&client, &token, image, image_layers, &temp_dir_path).await?;
-
-    // Scope to the temp dir with chroot
-    chroot_to_temp_dir(&temp_dir_path)?;
-
-    // HACK: Doesn't compile on macOS, run this program on Linux via docker
-    unsafe { libc::unshare(libc::CLONE_NEWPID) };
-
-    // Run the command
-    let output = std::process::Command::new(command)
-        .current_dir("/")
-        .args(command_args)
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .env_clear()
-        .output()
-        .with_context(|| format!("Tried to run '{}' ", command,))?;
-
-    if output.status.success() {
-        let std_out = std::str::from_utf8(&output.stdout)?;
-        let std_err = std::str::from_utf8(&output.stderr)?;
-        print!("{}", std_out);
-        eprint!("{}", std_err);
-    } else {
-        std::process::exit(output.status.code().unwrap_or(1))
+    let cli = cli::Cli::parse();
+
+    // Quiet by default so we don't interleave with the container's output. Verbosity only
+    // applies to our own logs, the HTTP stack's would be noise at best and headers at worst.
+    let level = match cli.verbose {
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+                tracing_subscriber::EnvFilter::new(format!("warn,docker_starter_rust={}", level))
+            }),
+        )
+        .init();
+
+    match cli.command {
+        cli::Command::Run(args) => run(*args).await,
+        cli::Command::Pull(args) => pull(*args).await,
+        cli::Command::Images(args) => list_images(args),
+        cli::Command::Rmi(args) => remove_image(args),
+        cli::Command::System {
+            command: cli::SystemCommand::Prune(args),
+        } => prune(args),
     }
-
-    Ok(())
 }
@@ -0,0 +1,526 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    ffi::{CStr, CString, OsString},
+    io,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::PermissionsExt,
+    },
+    path::{Path, PathBuf},
+    ptr,
+};
+
+fn mount(
+    source: &CStr,
+    target: &CStr,
+    fstype: Option<&CStr>,
+    flags: libc::c_ulong,
+) -> io::Result<()> {
+    mount_with_data(source, target, fstype, flags, None)
+}
+
+/// `mount` with filesystem specific options, like tmpfs' `mode=1777`.
+fn mount_with_data(
+    source: &CStr,
+    target: &CStr,
+    fstype: Option<&CStr>,
+    flags: libc::c_ulong,
+    data: Option<&CStr>,
+) -> io::Result<()> {
+    let fstype = fstype.map_or(ptr::null(), CStr::as_ptr);
+    let data = data.map_or(ptr::null(), |data| data.as_ptr().cast());
+    match unsafe { libc::mount(source.as_ptr(), target.as_ptr(), fstype, flags, data) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Stops mounts made in our (freshly unshared) mount namespace from propagating back to the
+/// host, which is what most distros' shared `/` would otherwise do.
+pub fn make_private() -> io::Result<()> {
+    mount(c"none", c"/", None, libc::MS_REC | libc::MS_PRIVATE)
+}
+
+/// Mounts a proc filesystem at `/proc`. It shows the PID namespace of whoever mounts it, so
+/// this has to run in the container's first process, between fork and exec.
+pub fn mount_proc() -> io::Result<()> {
+    mount(
+        c"proc",
+        c"/proc",
+        Some(c"proc"),
+        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+    )
+}
+
+/// Bind mounts `root` onto itself, making it a mount of its own that `remount_read_only` can
+/// later remount. Has to come before anything else is mounted into it, those would end up
+/// underneath.
+pub fn bind_root(root: &Path) -> Result<()> {
+    let root_c = c_path(root)?;
+    mount(&root_c, &root_c, None, libc::MS_BIND)
+        .with_context(|| format!("Failed to bind mount {}", root.display()))
+}
+
+/// Makes the root bound by `bind_root` read-only. What is mounted into it keeps its own
+/// flags, volumes and tmpfs mounts stay writable.
+pub fn remount_read_only(root: &Path) -> Result<()> {
+    let root_c = c_path(root)?;
+    mount(
+        &root_c,
+        &root_c,
+        None,
+        libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+    )
+    .context("Failed to make the root read-only")
+}
+
+/// The character devices every container gets, with their numbers on Linux.
+const DEVICES: [(&str, u32, u32); 6] = [
+    ("null", 1, 3),
+    ("zero", 1, 5),
+    ("full", 1, 7),
+    ("random", 1, 8),
+    ("urandom", 1, 9),
+    ("tty", 5, 0),
+];
+
+/// The links every container's /dev has, to /proc for the standard streams and to `pts` for
+/// the pseudo terminal multiplexer.
+const DEV_LINKS: [(&str, &str); 5] = [
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+    ("ptmx", "pts/ptmx"),
+];
+
+/// Sets up the container's /dev like Docker does: a tmpfs over whatever the image has there,
+/// with the standard devices, a devpts of its own at `pts` and a tmpfs for shared memory at
+/// `shm`. Where we aren't allowed to mknod, the host's devices are bound in instead.
+pub fn populate_dev(root: &Path) -> Result<()> {
+    let dev = docker_starter_rust::layer::resolve_all_in_root(root, Path::new("/dev"))?;
+    std::fs::create_dir_all(&dev)?;
+    mount_with_data(
+        c"tmpfs",
+        &c_path(&dev)?,
+        Some(c"tmpfs"),
+        libc::MS_NOSUID | libc::MS_STRICTATIME,
+        Some(c"mode=755,size=65536k"),
+    )
+    .context("Failed to mount a tmpfs at /dev")?;
+
+    for (name, major, minor) in DEVICES {
+        let path = dev.join(name);
+        let made = unsafe {
+            libc::mknod(
+                c_path(&path)?.as_ptr(),
+                libc::S_IFCHR | 0o666,
+                libc::makedev(major, minor),
+            )
+        };
+        if made != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::PermissionDenied {
+                return Err(err).with_context(|| format!("Failed to create /dev/{}", name));
+            }
+            std::fs::File::create(&path)?;
+            let host = c_path(&Path::new("/dev").join(name))?;
+            mount(&host, &c_path(&path)?, None, libc::MS_BIND)
+                .with_context(|| format!("Failed to bind /dev/{} from the host", name))?;
+            continue;
+        }
+        // mknod applies the umask
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666))?;
+    }
+    for (name, target) in DEV_LINKS {
+        std::os::unix::fs::symlink(target, dev.join(name))?;
+    }
+
+    // A new instance, the host's terminals stay out of sight
+    std::fs::create_dir(dev.join("pts"))?;
+    mount_with_data(
+        c"devpts",
+        &c_path(&dev.join("pts"))?,
+        Some(c"devpts"),
+        libc::MS_NOSUID | libc::MS_NOEXEC,
+        Some(c"newinstance,ptmxmode=0666,mode=0620,gid=5"),
+    )
+    .context("Failed to mount /dev/pts")?;
+    std::fs::create_dir(dev.join("shm"))?;
+    mount_with_data(
+        c"shm",
+        &c_path(&dev.join("shm"))?,
+        Some(c"tmpfs"),
+        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        Some(c"mode=1777,size=65536k"),
+    )
+    .context("Failed to mount /dev/shm")?;
+
+    Ok(())
+}
+
+/// A tmpfs to mount into the container, from `--tmpfs PATH[:OPTIONS]` or one of the defaults.
+#[derive(Debug, Clone)]
+pub struct Tmpfs {
+    pub target: PathBuf,
+    flags: libc::c_ulong,
+    /// The options tmpfs itself takes, like `size=64m,mode=1777`
+    data: String,
+}
+
+/// Options of `--tmpfs` that are mount flags rather than tmpfs ones, and the flags they set and
+/// clear.
+const TMPFS_FLAGS: [(&str, libc::c_ulong, libc::c_ulong); 7] = [
+    ("ro", libc::MS_RDONLY, 0),
+    ("rw", 0, libc::MS_RDONLY),
+    ("noexec", libc::MS_NOEXEC, 0),
+    ("exec", 0, libc::MS_NOEXEC),
+    ("nosuid", libc::MS_NOSUID, 0),
+    ("suid", 0, libc::MS_NOSUID),
+    ("nodev", libc::MS_NODEV, 0),
+];
+
+/// The options tmpfs takes, anything else is most likely a typo.
+const TMPFS_OPTIONS: [&str; 6] = ["size", "mode", "uid", "gid", "nr_inodes", "nr_blocks"];
+
+impl Tmpfs {
+    /// A world writable tmpfs like `/tmp`, unless `mode` says otherwise.
+    fn new(target: &str, mode: &str) -> Self {
+        Tmpfs {
+            target: PathBuf::from(target),
+            flags: libc::MS_NOSUID | libc::MS_NODEV,
+            data: format!("mode={}", mode),
+        }
+    }
+
+    /// What every container gets, for pidfiles, sockets and the like that shouldn't end up in
+    /// the root, let alone be there on the next run.
+    pub fn defaults() -> [Tmpfs; 2] {
+        [Tmpfs::new("/tmp", "1777"), Tmpfs::new("/run", "755")]
+    }
+}
+
+/// Parses `--tmpfs PATH[:OPTIONS]`, where the path has to be absolute like a volume's and the
+/// options are comma separated mount flags like `noexec` and tmpfs options like `size=64m`.
+pub fn parse_tmpfs(spec: &str) -> Result<Tmpfs> {
+    let (target, options) = spec.split_once(':').unwrap_or((spec, ""));
+    if !Path::new(target).is_absolute() {
+        bail!("the container path has to be absolute");
+    }
+
+    let mut tmpfs = Tmpfs::new(target, "1777");
+    let mut data = Vec::new();
+    for option in options.split(',').filter(|option| !option.is_empty()) {
+        if let Some((_, set, clear)) = TMPFS_FLAGS.iter().find(|(name, ..)| *name == option) {
+            tmpfs.flags = (tmpfs.flags | set) & !clear;
+            continue;
+        }
+        match option.split_once('=') {
+            Some((key, value)) if TMPFS_OPTIONS.contains(&key) && !value.is_empty() => {
+                data.retain(|entry: &&str| !entry.starts_with(&format!("{}=", key)));
+                data.push(option);
+            }
+            _ => bail!("unknown tmpfs option '{}'", option),
+        }
+    }
+    if !data.iter().any(|entry| entry.starts_with("mode=")) {
+        data.push("mode=1777");
+    }
+    tmpfs.data = data.join(",");
+
+    Ok(tmpfs)
+}
+
+/// Mounts `tmpfs` inside `root`, where its target is resolved and created like a volume's.
+pub fn mount_tmpfs(root: &Path, tmpfs: &Tmpfs) -> Result<()> {
+    let resolved = docker_starter_rust::layer::resolve_all_in_root(root, &tmpfs.target)?;
+    std::fs::create_dir_all(&resolved)?;
+    let data = CString::new(tmpfs.data.as_str()).context("tmpfs options contain a NUL byte")?;
+    mount_with_data(
+        c"tmpfs",
+        &c_path(&resolved)?,
+        Some(c"tmpfs"),
+        tmpfs.flags,
+        Some(&data),
+    )
+    .with_context(|| format!("Failed to mount a tmpfs at {}", tmpfs.target.display()))
+}
+
+/// Mounts a read-only sysfs at `root/sys`. It shows the network devices of our network
+/// namespace, which by now is the container's.
+pub fn mount_sysfs(root: &Path) -> Result<()> {
+    let target = docker_starter_rust::layer::resolve_all_in_root(root, Path::new("/sys"))?;
+    std::fs::create_dir_all(&target)?;
+    mount(
+        c"sysfs",
+        &c_path(&target)?,
+        Some(c"sysfs"),
+        libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+    )
+    .context("Failed to mount /sys")
+}
+
+/// Binds the container's cgroup onto `root/sys/fs/cgroup`, read-only, so that what reads its
+/// limits from there (Java sizing its heap, for one) sees the container's and nothing above
+/// them. Has to come after `mount_sysfs`, which provides the mount point.
+pub fn bind_cgroup(root: &Path, cgroup: &Path) -> Result<()> {
+    let target =
+        docker_starter_rust::layer::resolve_all_in_root(root, Path::new("/sys/fs/cgroup"))?;
+    let target = c_path(&target)?;
+    let source = c_path(cgroup)?;
+    mount(&source, &target, None, libc::MS_BIND)
+        .and_then(|()| {
+            mount(
+                &source,
+                &target,
+                None,
+                libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+            )
+        })
+        .context("Failed to mount /sys/fs/cgroup")
+}
+
+/// A host path to bind into the container, from `-v HOST:CONTAINER[:ro]`.
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub host: PathBuf,
+    pub target: PathBuf,
+    pub read_only: bool,
+}
+
+pub fn parse_volume(spec: &str) -> Result<Volume> {
+    let mut parts = spec.split(':');
+    let (Some(host), Some(target)) = (parts.next(), parts.next()) else {
+        bail!("expected HOST:CONTAINER[:ro]");
+    };
+    let read_only = match parts.next() {
+        None | Some("rw") => false,
+        Some("ro") => true,
+        Some(mode) => bail!("unknown volume mode '{}', expected ro or rw", mode),
+    };
+    if parts.next().is_some() {
+        bail!("expected HOST:CONTAINER[:ro]");
+    }
+
+    if host.is_empty() {
+        bail!("the host path is empty, expected HOST:CONTAINER[:ro]");
+    }
+    let (host, target) = (PathBuf::from(host), PathBuf::from(target));
+    // Unlike Docker, which would take a relative one for a named volume
+    let host = std::env::current_dir()?.join(host);
+    if !host.exists() {
+        bail!("{} doesn't exist", host.display());
+    }
+    if !target.is_absolute() {
+        bail!("the container path has to be absolute");
+    }
+
+    Ok(Volume {
+        host,
+        target,
+        read_only,
+    })
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Path {} contains a NUL byte", path.display()))
+}
+
+/// Binds the volume's host path onto its target in `root`, creating the target if it is
+/// missing. The target is resolved inside the root, symlinks in the image can't point the mount
+/// anywhere else. Has to happen in our own mount namespace, unmounting happens with the rest of
+/// the root.
+pub fn bind_volume(root: &Path, volume: &Volume) -> Result<()> {
+    let target = docker_starter_rust::layer::resolve_all_in_root(root, &volume.target)?;
+    if volume.host.is_dir() {
+        std::fs::create_dir_all(&target)?;
+    } else {
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if !target.exists() {
+            std::fs::File::create(&target)?;
+        }
+    }
+
+    let (host, target_c) = (c_path(&volume.host)?, c_path(&target)?);
+    mount(&host, &target_c, None, libc::MS_BIND | libc::MS_REC).with_context(|| {
+        format!(
+            "Failed to mount {} at {}",
+            volume.host.display(),
+            volume.target.display()
+        )
+    })?;
+    // Bind mounts only take flags other than MS_BIND when remounted
+    if volume.read_only {
+        mount(
+            &host,
+            &target_c,
+            None,
+            libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+        )
+        .with_context(|| format!("Failed to make {} read-only", volume.target.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Mounts the volumes and tmpfs mounts in `root`, along with whichever of the default tmpfs
+/// mounts neither replaces nor covers. Shallower targets go first, so that `-v HOST:/tmp/x`
+/// ends up on top of the tmpfs at `/tmp` rather than hidden below it.
+pub fn mount_all(root: &Path, volumes: &[Volume], tmpfs: &[Tmpfs]) -> Result<()> {
+    enum Mount<'a> {
+        Volume(&'a Volume),
+        Tmpfs(&'a Tmpfs),
+    }
+
+    let defaults = default_tmpfs(volumes, tmpfs);
+    let mut mounts: Vec<(&Path, Mount)> = volumes
+        .iter()
+        .map(|volume| (volume.target.as_path(), Mount::Volume(volume)))
+        .chain(
+            defaults
+                .iter()
+                .chain(tmpfs)
+                .map(|tmpfs| (tmpfs.target.as_path(), Mount::Tmpfs(tmpfs))),
+        )
+        .collect();
+    mounts.sort_by_key(|(target, _)| target.components().count());
+
+    for (_, mount) in mounts {
+        match mount {
+            Mount::Volume(volume) => bind_volume(root, volume)?,
+            Mount::Tmpfs(tmpfs) => mount_tmpfs(root, tmpfs)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// The default tmpfs mounts that are still wanted: not replaced by a `--tmpfs` at the same
+/// path, and not overlapping a volume, which either covers the default or sits inside it and
+/// is meant to show the host's files there rather than an empty tmpfs around them.
+fn default_tmpfs(volumes: &[Volume], tmpfs: &[Tmpfs]) -> Vec<Tmpfs> {
+    Tmpfs::defaults()
+        .into_iter()
+        .filter(|default| !tmpfs.iter().any(|tmpfs| tmpfs.target == default.target))
+        .filter(|default| {
+            !volumes.iter().any(|volume| {
+                volume.target.starts_with(&default.target)
+                    || default.target.starts_with(&volume.target)
+            })
+        })
+        .collect()
+}
+
+/// Undoes the octal escapes mountinfo uses for spaces and other odd bytes in paths.
+fn unescape(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut path = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                path.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                path.push(byte);
+                i += 1;
+            }
+        }
+    }
+    PathBuf::from(OsString::from_vec(path))
+}
+
+/// Detaches every mount at or below `dir` in our mount namespace, newest first. mountinfo
+/// lists mounts in the order they were made, so going backwards undoes one that covers an
+/// earlier one before the earlier one, deeper or not. The mount namespace belongs to this
+/// thread, so this has to run on the one that unshared it.
+pub fn unmount_under(dir: &Path) -> io::Result<()> {
+    let mountinfo = std::fs::read_to_string("/proc/thread-self/mountinfo")?;
+    let targets: Vec<PathBuf> = mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(unescape)
+        .filter(|target| target.starts_with(dir))
+        .collect();
+
+    for target in targets.into_iter().rev() {
+        let target = CString::new(target.as_os_str().as_bytes())?;
+        if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_volumes() {
+        let volume = parse_volume("/tmp:/data:ro").unwrap();
+        assert_eq!(volume.host, Path::new("/tmp"));
+        assert_eq!(volume.target, Path::new("/data"));
+        assert!(volume.read_only);
+        assert!(!parse_volume("/tmp:/data").unwrap().read_only);
+        assert!(!parse_volume("/tmp:/data:rw").unwrap().read_only);
+    }
+
+    #[test]
+    fn resolves_relative_host_paths() {
+        let volume = parse_volume(".:/data").unwrap();
+        assert_eq!(volume.host, std::env::current_dir().unwrap().join("."));
+    }
+
+    #[test]
+    fn volumes_replace_overlapping_default_tmpfs() {
+        let targets = |volumes: &[&str], tmpfs: &[&str]| {
+            let volumes: Vec<Volume> = volumes
+                .iter()
+                .map(|target| parse_volume(&format!("/tmp:{}", target)).unwrap())
+                .collect();
+            let tmpfs: Vec<Tmpfs> = tmpfs
+                .iter()
+                .map(|spec| parse_tmpfs(spec).unwrap())
+                .collect();
+            default_tmpfs(&volumes, &tmpfs)
+                .into_iter()
+                .map(|tmpfs| tmpfs.target)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(targets(&[], &[]), [Path::new("/tmp"), Path::new("/run")]);
+        assert_eq!(
+            targets(&["/data"], &[]),
+            [Path::new("/tmp"), Path::new("/run")]
+        );
+        assert_eq!(targets(&["/tmp"], &[]), [Path::new("/run")]);
+        assert_eq!(targets(&["/run/x"], &[]), [Path::new("/tmp")]);
+        assert_eq!(targets(&["/"], &[]), Vec::<PathBuf>::new());
+        assert_eq!(
+            targets(&["/tmpdir"], &["/run:size=1m"]),
+            [Path::new("/tmp")]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_volumes() {
+        for spec in [
+            ":/data",
+            "/tmp",
+            "/tmp:data",
+            "/tmp:/data:rx",
+            "/tmp:/data:ro:extra",
+            "/does/not/exist:/data",
+        ] {
+            assert!(parse_volume(spec).is_err(), "{} was accepted", spec);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use anyhow::{Context, Result};
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+/// Brings up `lo`, which a new network namespace starts out with but down, so that servers in
+/// the container can at least talk to each other over localhost. Anything beyond that would
+/// need a veth pair into a bridge on the host, which we don't set up.
+pub fn loopback_up() -> Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to open a socket");
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(b"lo") {
+        *dst = *src as libc::c_char;
+    }
+
+    let result = unsafe {
+        let fd = socket.as_raw_fd();
+        if libc::ioctl(fd, libc::SIOCGIFFLAGS, &mut request) == 0 {
+            request.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+            libc::ioctl(fd, libc::SIOCSIFFLAGS, &request)
+        } else {
+            -1
+        }
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error())
+            .context("Failed to bring up the loopback interface");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,149 @@
+use anyhow::{bail, Error};
+use std::{fmt, str::FromStr};
+
+/// An `os/architecture[/variant]` platform, as used both in manifest lists and `--platform`.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Whether a manifest published for `candidate` can run on this platform. Leaving the
+    /// variant out matches any variant of the architecture, while asking for one only matches
+    /// that variant or images that don't declare one (so `v7` never picks a `v6` build).
+    pub fn matches(&self, candidate: &Platform) -> bool {
+        self.os == candidate.os
+            && self.architecture == candidate.architecture
+            && (self.variant.is_none()
+                || candidate.variant.is_none()
+                || self.variant == candidate.variant)
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Platform {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.split('/').collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            bail!("Invalid platform '{}', expected [os/]arch[/variant]", s);
+        }
+
+        match parts.as_slice() {
+            // Like docker, a bare architecture means a linux image
+            [architecture] => Ok(Platform {
+                os: "linux".to_owned(),
+                architecture: architecture.to_string(),
+                variant: None,
+            }),
+            [os, architecture] => Ok(Platform {
+                os: os.to_string(),
+                architecture: architecture.to_string(),
+                variant: None,
+            }),
+            [os, architecture, variant] => Ok(Platform {
+                os: os.to_string(),
+                architecture: architecture.to_string(),
+                variant: Some(variant.to_string()),
+            }),
+            _ => bail!("Invalid platform '{}', expected [os/]arch[/variant]", s),
+        }
+    }
+}
+
+/// Maps a kernel machine name (`uname -m`) onto the architecture and variant used in OCI
+/// manifest lists.
+pub fn map_machine(machine: &str) -> (&str, Option<&str>) {
+    match machine {
+        "x86_64" | "amd64" => ("amd64", None),
+        "aarch64" | "arm64" => ("arm64", None),
+        "i386" | "i686" | "x86" => ("386", None),
+        "armv7l" | "armv7" => ("arm", Some("v7")),
+        "armv6l" | "armv6" => ("arm", Some("v6")),
+        "armv5l" | "armv5tel" => ("arm", Some("v5")),
+        "ppc64le" => ("ppc64le", None),
+        // Big endian, `ppc64le` is the one most images are built for
+        "ppc64" | "powerpc64" => ("ppc64", None),
+        "s390x" => ("s390x", None),
+        "riscv64" => ("riscv64", None),
+        other => (other, None),
+    }
+}
+
+/// The machine name the running kernel reports, which unlike `std::env::consts::ARCH` knows
+/// about arm variants.
+fn uname_machine() -> Option<String> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return None;
+    }
+
+    let machine = unsafe { std::ffi::CStr::from_ptr(uts.machine.as_ptr()) };
+    machine.to_str().ok().map(str::to_owned)
+}
+
+/// The platform containers run on when `--platform` isn't given.
+pub fn host_platform() -> Platform {
+    let machine = uname_machine().unwrap_or_else(|| std::env::consts::ARCH.to_owned());
+    let (architecture, variant) = map_machine(&machine);
+
+    Platform {
+        os: "linux".to_owned(),
+        architecture: architecture.to_owned(),
+        variant: variant.map(str::to_owned),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_kernel_machine_names() {
+        assert_eq!(map_machine("x86_64"), ("amd64", None));
+        assert_eq!(map_machine("aarch64"), ("arm64", None));
+        assert_eq!(map_machine("i686"), ("386", None));
+        assert_eq!(map_machine("armv7l"), ("arm", Some("v7")));
+        assert_eq!(map_machine("armv6l"), ("arm", Some("v6")));
+        assert_eq!(map_machine("ppc64le"), ("ppc64le", None));
+        assert_eq!(map_machine("ppc64"), ("ppc64", None));
+        assert_eq!(map_machine("powerpc64"), ("ppc64", None));
+        assert_eq!(map_machine("s390x"), ("s390x", None));
+        assert_eq!(map_machine("mips"), ("mips", None));
+    }
+
+    #[test]
+    fn parses_platforms() {
+        let platform: Platform = "linux/arm/v7".parse().unwrap();
+        assert_eq!(platform.to_string(), "linux/arm/v7");
+        assert_eq!(
+            "arm64".parse::<Platform>().unwrap().to_string(),
+            "linux/arm64"
+        );
+        assert!("linux//v7".parse::<Platform>().is_err());
+        assert!("a/b/c/d".parse::<Platform>().is_err());
+    }
+
+    #[test]
+    fn a_variant_only_matches_itself_or_none() {
+        let v7: Platform = "linux/arm/v7".parse().unwrap();
+        let arm: Platform = "linux/arm".parse().unwrap();
+        assert!(v7.matches(&arm));
+        assert!(arm.matches(&"linux/arm/v6".parse().unwrap()));
+        assert!(!v7.matches(&"linux/arm/v6".parse().unwrap()));
+        assert!(!v7.matches(&"windows/arm/v7".parse().unwrap()));
+    }
+}
@@ -0,0 +1,159 @@
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use std::{
+    io::IsTerminal,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How often a layer's progress is logged when there is no terminal to draw bars on.
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+enum Mode {
+    Bars(MultiProgress),
+    /// Plain lines for logs and CI, where redrawing bars would just spam
+    Log,
+    Quiet,
+}
+
+/// Download progress of a whole image: a bar per layer in flight and one for the total.
+pub struct Progress {
+    mode: Mode,
+    total: ProgressBar,
+}
+
+impl Progress {
+    /// Bars are drawn on stderr, so that is the stream that has to be a terminal.
+    pub fn new(quiet: bool, total_bytes: u64) -> Self {
+        let mode = if quiet {
+            Mode::Quiet
+        } else if std::io::stderr().is_terminal() {
+            Mode::Bars(MultiProgress::new())
+        } else {
+            Mode::Log
+        };
+
+        let total = match &mode {
+            Mode::Bars(multi) => {
+                let bar = multi.add(ProgressBar::new(total_bytes));
+                bar.set_style(style("{msg:>12} [{bar:40}] {bytes}/{total_bytes}"));
+                bar.set_message("Total");
+                bar
+            }
+            _ => ProgressBar::hidden(),
+        };
+        total.set_length(total_bytes);
+
+        Progress { mode, total }
+    }
+
+    /// A bar for the blob `digest`, sized from the manifest until the registry says otherwise.
+    pub fn layer(&self, digest: &str, size: u64) -> LayerProgress {
+        let name = short_digest(digest);
+        let bar = match &self.mode {
+            Mode::Bars(multi) => {
+                let bar = multi.insert_before(&self.total, ProgressBar::new(size));
+                bar.set_style(style(
+                    "{msg:>12} [{bar:40}] {bytes}/{total_bytes} {bytes_per_sec}",
+                ));
+                bar.set_message(name.clone());
+                bar
+            }
+            _ => ProgressBar::hidden(),
+        };
+
+        LayerProgress {
+            bar,
+            total: self.total.clone(),
+            log: matches!(self.mode, Mode::Log),
+            name,
+            last_log: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Counts a layer that didn't need downloading towards the total.
+    pub fn skip(&self, size: u64) {
+        self.total.inc(size);
+    }
+
+    pub fn finish(&self) {
+        self.total.finish_and_clear();
+        if let Mode::Bars(multi) = &self.mode {
+            // Nothing should linger above the output of the container
+            let _ = multi.clear();
+        }
+    }
+}
+
+/// Progress of a single blob download.
+pub struct LayerProgress {
+    bar: ProgressBar,
+    total: ProgressBar,
+    log: bool,
+    name: String,
+    last_log: Mutex<Instant>,
+}
+
+impl LayerProgress {
+    /// (Re)starts the bar at `position`, which is non zero when resuming. `remaining` is the
+    /// Content-Length of the response, if the registry sent one.
+    pub fn start(&self, position: u64, remaining: Option<u64>) {
+        if let Some(remaining) = remaining {
+            self.bar.set_length(position + remaining);
+        }
+        self.set_position(position);
+    }
+
+    pub fn inc(&self, bytes: u64) {
+        self.set_position(self.bar.position() + bytes);
+
+        if self.log {
+            let mut last_log = self.last_log.lock().unwrap();
+            if last_log.elapsed() >= LOG_INTERVAL {
+                *last_log = Instant::now();
+                eprintln!(
+                    "{}: {} of {}",
+                    self.name,
+                    HumanBytes(self.bar.position()),
+                    HumanBytes(self.bar.length().unwrap_or_default())
+                );
+            }
+        }
+    }
+
+    /// Logs that the layer is complete, the bar itself goes away once this is dropped.
+    pub fn finish(&self) {
+        if self.log {
+            eprintln!(
+                "{}: downloaded {}",
+                self.name,
+                HumanBytes(self.bar.position())
+            );
+        }
+    }
+
+    /// Moves the total along with this layer, also backwards if a download had to start over.
+    fn set_position(&self, position: u64) {
+        let total = self.total.position() + position;
+        self.total
+            .set_position(total.saturating_sub(self.bar.position()));
+        self.bar.set_position(position);
+    }
+}
+
+impl Drop for LayerProgress {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("progress bar template is valid")
+        .progress_chars("=> ")
+}
+
+/// The first 12 hex digits, the way Docker names layers while pulling.
+fn short_digest(digest: &str) -> String {
+    let hex = digest.split_once(':').map_or(digest, |(_, hex)| hex);
+    hex.chars().take(12).collect()
+}
@@ -0,0 +1,734 @@
+use anyhow::Result;
+use clap::Parser;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    auth, cache, config, credentials, error::PullError, http, images, layer, platform,
+    platform::Platform, reference, registry, retry, token_cache,
+};
+
+/// How to pull an image. These double as the command line options of every subcommand that
+/// pulls one.
+#[derive(Parser, Debug)]
+pub struct PullOptions {
+    /// Platform to pull, e.g. `linux/amd64` or `linux/arm/v7` (defaults to the host platform)
+    #[arg(long)]
+    pub platform: Option<Platform>,
+
+    /// Talk to this registry over plain HTTP, by host or `host:port` (localhost always is)
+    #[arg(long, value_name = "HOST")]
+    pub insecure_registry: Vec<String>,
+
+    /// Also trust the CA certificates in this PEM file
+    #[arg(long, value_name = "FILE")]
+    pub registry_ca: Vec<PathBuf>,
+
+    /// Client certificate (PEM) to present to the registry
+    #[arg(long, value_name = "FILE", requires = "registry_key")]
+    pub registry_cert: Option<PathBuf>,
+
+    /// Private key (PKCS#8 PEM) for `--registry-cert`
+    #[arg(long, value_name = "FILE", requires = "registry_cert")]
+    pub registry_key: Option<PathBuf>,
+
+    /// Don't verify the TLS certificate of this registry, by host or `host:port`
+    #[arg(long, value_name = "HOST")]
+    pub insecure_skip_tls_verify: Vec<String>,
+
+    /// Proxy for all registry requests, instead of `HTTPS_PROXY` and `HTTP_PROXY`
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<String>,
+
+    /// Comma separated hosts to reach without the proxy, instead of `NO_PROXY`
+    #[arg(long, value_name = "HOSTS")]
+    pub no_proxy: Option<String>,
+
+    /// Pull Docker Hub images through this mirror instead of Docker Hub itself. Mirrors are
+    /// tried in the order given
+    #[arg(long, value_name = "URL")]
+    pub registry_mirror: Vec<String>,
+
+    /// Fall back to Docker Hub itself when none of the mirrors has the image
+    #[arg(long, requires = "registry_mirror")]
+    pub mirror_fallback: bool,
+
+    /// Longest time in seconds to wait between retries of a failing request
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    pub max_retry_delay: u64,
+
+    /// How often to retry a request that failed with a network error or a 5xx status
+    #[arg(long, default_value_t = 3)]
+    pub retries: u32,
+
+    /// Total seconds to wait on a registry that rate limits us (429) before giving up
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    pub max_rate_limit_wait: u64,
+
+    /// Seconds to wait for a connection to the registry
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub connect_timeout: u64,
+
+    /// Seconds a registry request may take, except for layer downloads
+    #[arg(
+        long,
+        alias = "request-timeout",
+        value_name = "SECONDS",
+        default_value_t = 60
+    )]
+    pub timeout: u64,
+
+    /// Seconds a layer download may go without receiving any data
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    pub read_timeout: u64,
+
+    /// Number of layers to download at the same time
+    #[arg(long, default_value_t = 3)]
+    pub max_concurrent_downloads: usize,
+
+    /// Leave out foreign layers, the ones registries don't distribute, instead of downloading
+    /// them from where their manifest points
+    #[arg(long)]
+    pub skip_foreign_layers: bool,
+
+    /// Always download layers instead of using the local blob cache
+    #[arg(long)]
+    pub no_cache: bool,
+
+    /// Log in to the registry as this user instead of using the docker config
+    /// (or set `MYDOCKER_USERNAME`)
+    #[arg(long)]
+    pub username: Option<String>,
+
+    /// Read the registry password from stdin (or set `MYDOCKER_PASSWORD`)
+    #[arg(long)]
+    pub password_stdin: bool,
+
+    /// Always ask the registry for a new token instead of reusing one from an earlier pull
+    #[arg(long)]
+    pub no_token_cache: bool,
+
+    /// Directory for the layer cache (defaults to `~/.mydocker/layers`)
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Don't show download progress
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// After pulling, evict unused layers from the cache until it fits, e.g. `10G`
+    #[arg(long, value_name = "SIZE", value_parser = cache::parse_size)]
+    pub cache_max_size: Option<u64>,
+
+    /// Give up on images that unpack to more than this, so a tar bomb can't fill the disk
+    #[arg(long, value_name = "SIZE", default_value = "64G", value_parser = cache::parse_size)]
+    pub max_unpacked_size: u64,
+
+    /// Where to keep track of the layers unpacked into the destination, for a destination kept
+    /// between pulls. Pulling into it again then only downloads and unpacks the layers it is
+    /// missing, after emptying it if it holds anything but the start of this image
+    #[arg(skip)]
+    pub applied_layers: Option<PathBuf>,
+
+    /// Use an image that is already in the local store with all of its layers as is, without
+    /// asking the registry whether the tag moved, the way `docker run` does
+    #[arg(skip)]
+    pub prefer_local: bool,
+}
+
+impl Default for PullOptions {
+    /// The same defaults as on the command line.
+    fn default() -> Self {
+        PullOptions::parse_from(["mydocker"])
+    }
+}
+
+/// Pulls `image_name` (anything `docker pull` takes) and unpacks it into `temp_dir_path`,
+/// returning its digest and config. Pulled layers are kept in the blob cache and the image in the local
+/// image store, unless `options.no_cache` is set.
+pub async fn pull_image(
+    image_name: &str,
+    temp_dir_path: &Path,
+    options: &PullOptions,
+) -> Result<PulledImage, PullError> {
+    let reference = reference::parse_image_reference(image_name)?;
+    let platform = options
+        .platform
+        .clone()
+        .unwrap_or_else(platform::host_platform);
+    tracing::info!("Using platform {}", platform);
+
+    // The image store only points into the cache, so it is of no use without one
+    let local = match options.no_cache {
+        true => None,
+        false => Some((
+            cache::BlobCache::open(options.cache_dir.as_deref())?,
+            images::ImageStore::new(&images::ImageStore::default_dir())?,
+        )),
+    };
+
+    if let (true, Some((cache, store))) = (options.prefer_local, &local) {
+        match store.get(&reference, &platform) {
+            Ok(Some(record)) => match images::cached_blobs(&record, cache) {
+                Some((config, layers)) => {
+                    tracing::info!("Using local image {}", record.digest);
+                    let config = registry::read_image_config(&config)?;
+                    let mut budget = layer::SizeBudget::new(options.max_unpacked_size);
+                    let mut layers: Vec<_> = record.layers.iter().zip(layers).collect();
+                    for (digest, _) in &layers {
+                        cache.touch(digest);
+                    }
+                    // Same as when pulling, a repeated layer changes nothing
+                    layers.dedup_by(|(digest, _), (previous, _)| digest == previous);
+                    let mut applied = match &options.applied_layers {
+                        Some(path) => {
+                            let digests: Vec<_> = layers
+                                .iter()
+                                .map(|(digest, _)| digest.to_string())
+                                .collect();
+                            let (applied, skip) =
+                                layer::AppliedLayers::open(path, temp_dir_path, &digests)?;
+                            layers.drain(..skip);
+                            Some(applied)
+                        }
+                        None => None,
+                    };
+                    for (digest, layer) in layers {
+                        // The image store doesn't keep media types
+                        layer::unpack_layer(&layer, None, temp_dir_path, &mut budget).map_err(
+                            |err| PullError::Extraction {
+                                digest: digest.clone(),
+                                source: err.into(),
+                            },
+                        )?;
+                        if let Some(applied) = &mut applied {
+                            applied.push(digest)?;
+                        }
+                    }
+                    log_exposed_ports(&config);
+                    return Ok(PulledImage {
+                        digest: record.digest,
+                        config,
+                    });
+                }
+                None => tracing::warn!(
+                    "Local image {} is missing layers, pulling it again",
+                    image_name
+                ),
+            },
+            Ok(None) => {}
+            Err(err) => tracing::warn!("{:#}, pulling the image again", err),
+        }
+    }
+
+    let clients = build_clients(&reference, options)?;
+    let cache = local.as_ref().map(|(cache, _)| cache);
+    let pulled = try_sources(sources(&reference, options)?, |source| {
+        pull_from(
+            &clients,
+            source,
+            &reference,
+            options,
+            &platform,
+            temp_dir_path,
+            cache,
+        )
+    })
+    .await?;
+
+    if let Some((_, store)) = &local {
+        let record = images::ImageRecord {
+            registry: reference.registry.clone(),
+            repository: reference.repository.clone(),
+            // A bare name is pulled as `latest`, a bare digest has no tag
+            tag: reference
+                .tag
+                .clone()
+                .or_else(|| reference.digest.is_none().then(|| "latest".to_owned())),
+            digest: pulled.digest.clone(),
+            platform: pulled.platform,
+            config: Some(pulled.config_digest),
+            layers: pulled.layers,
+        };
+        store.save(&reference, &platform, &record)?;
+    }
+
+    if let (Some(max_size), Some((cache, store))) = (options.cache_max_size, &local) {
+        let report = cache.prune(&images::layers_in_use(store)?, Some(max_size), false)?;
+        if report.blobs > 0 {
+            tracing::info!(
+                "Evicted {} layers ({} bytes) from the cache",
+                report.blobs,
+                report.bytes
+            );
+        }
+    }
+
+    log_exposed_ports(&pulled.config);
+    Ok(PulledImage {
+        digest: pulled.digest,
+        config: pulled.config,
+    })
+}
+
+fn log_exposed_ports(config: &config::ImageConfig) {
+    let ports = config.exposed_ports();
+    if !ports.is_empty() {
+        tracing::info!("Image exposes ports {}", ports.join(", "));
+    }
+}
+
+/// Resolves `image_name` to the image for our platform and fetches its config, without
+/// downloading any layers. For `--print-config`.
+pub async fn inspect_image(
+    image_name: &str,
+    options: &PullOptions,
+) -> Result<ImageInfo, PullError> {
+    let reference = reference::parse_image_reference(image_name)?;
+    let platform = options
+        .platform
+        .clone()
+        .unwrap_or_else(platform::host_platform);
+    let cache = match options.no_cache {
+        true => None,
+        false => Some(cache::BlobCache::open(options.cache_dir.as_deref())?),
+    };
+
+    let clients = build_clients(&reference, options)?;
+    let resolved = try_sources(sources(&reference, options)?, |source| {
+        resolve_from(
+            &clients,
+            source,
+            &reference,
+            options,
+            &platform,
+            cache.as_ref(),
+        )
+    })
+    .await?;
+
+    Ok(ImageInfo {
+        platform: resolved.image.platform.to_string(),
+        digest: resolved.image.digest,
+        layers: resolved
+            .image
+            .manifest
+            .layers
+            .into_iter()
+            .map(|layer| LayerInfo {
+                digest: layer.digest,
+                size: layer.size,
+                media_type: layer.media_type,
+            })
+            .collect(),
+        config: resolved.config.config.unwrap_or_default(),
+    })
+}
+
+/// What `inspect_image` found out about an image. Serializes with every key present, `null`
+/// for what the image doesn't set, so that the output's shape doesn't depend on the image.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageInfo {
+    pub platform: String,
+    /// Digest of the platform specific image manifest
+    pub digest: String,
+    pub layers: Vec<LayerInfo>,
+    /// `Env`, `Entrypoint`, `Cmd` and the rest, at the top level
+    #[serde(flatten)]
+    pub config: config::ContainerConfig,
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct LayerInfo {
+    pub digest: String,
+    pub size: u64,
+    pub media_type: Option<String>,
+}
+
+/// The image `pull_image` unpacked.
+#[derive(Debug)]
+pub struct PulledImage {
+    /// Digest of the platform specific image manifest
+    pub digest: String,
+    pub config: config::ImageConfig,
+}
+
+/// A registry to pull from, the image's own or a mirror of it.
+struct Source {
+    url: String,
+    credentials: Option<credentials::Credentials>,
+}
+
+/// Builds the clients for pulling `reference`. Skipping verification for the registry only
+/// applies to requests to the registry itself.
+fn build_clients(
+    reference: &reference::ImageReference,
+    options: &PullOptions,
+) -> Result<http::Clients> {
+    let skip_verify = reference.registry_in(&options.insecure_skip_tls_verify);
+    if skip_verify {
+        tracing::warn!(
+            "Not verifying the TLS certificate of {}",
+            reference.registry
+        );
+    }
+    http::build_clients(
+        &http::Timeouts {
+            connect: Duration::from_secs(options.connect_timeout),
+            request: Duration::from_secs(options.timeout),
+        },
+        &http::Tls {
+            ca_files: options.registry_ca.clone(),
+            identity: options
+                .registry_cert
+                .clone()
+                .zip(options.registry_key.clone()),
+            skip_verify: skip_verify.then(|| reference.registry.clone()),
+        },
+        &http::Proxies::new(options.proxy.as_deref(), options.no_proxy.as_deref())?,
+    )
+}
+
+/// Where to get `reference` from: Docker Hub images come from the mirrors if there are any, and
+/// only with `mirror_fallback` from Docker Hub itself after them. Each has its own token, a
+/// mirror's wouldn't be valid anywhere else.
+fn sources(reference: &reference::ImageReference, options: &PullOptions) -> Result<Vec<Source>> {
+    let mut sources = Vec::new();
+    if reference.registry == reference::DEFAULT_REGISTRY {
+        for mirror in &options.registry_mirror {
+            let url = match mirror.contains("://") {
+                true => mirror.trim_end_matches('/').to_owned(),
+                false => format!("https://{}", mirror.trim_end_matches('/')),
+            };
+            let host = url.split_once("://").map_or(url.as_str(), |(_, host)| host);
+            let credentials = credentials::load_credentials(host);
+            sources.push(Source { url, credentials });
+        }
+        if !sources.is_empty() && !options.mirror_fallback {
+            return Ok(sources);
+        }
+    }
+    let credentials = match credentials::explicit_credentials(
+        options.username.as_deref(),
+        options.password_stdin,
+    )? {
+        Some(credentials) => Some(credentials),
+        None => credentials::load_credentials(&reference.registry),
+    };
+    sources.push(Source {
+        url: reference.registry_url(&options.insecure_registry),
+        credentials,
+    });
+
+    Ok(sources)
+}
+
+/// Runs `attempt` against each source in turn until one succeeds, returning the last one's
+/// error if none does.
+async fn try_sources<T, F, Fut>(sources: Vec<Source>, mut attempt: F) -> Result<T>
+where
+    F: FnMut(Source) -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut sources = sources.into_iter().peekable();
+    loop {
+        let source = sources.next().expect("there is always the registry itself");
+        let url = source.url.clone();
+        match attempt(source).await {
+            Ok(value) => return Ok(value),
+            Err(err) => match sources.peek() {
+                Some(next) => tracing::warn!(
+                    "Pulling from {} failed, trying {}: {:#}",
+                    url,
+                    next.url,
+                    err
+                ),
+                None => return Err(err),
+            },
+        }
+    }
+}
+
+/// What `pull_from` got, to record in the image store.
+struct Pulled {
+    digest: String,
+    platform: platform::Platform,
+    config_digest: String,
+    layers: Vec<String>,
+    config: config::ImageConfig,
+}
+
+/// An image manifest and config resolved from a source, with the tokens to fetch its layers.
+struct Resolved {
+    source: Source,
+    tokens: auth::TokenManager,
+    image: registry::ResolvedImage,
+    config: config::ImageConfig,
+}
+
+/// Gets the manifest of the image for our platform from `source`, going through the manifest
+/// list if there is one, and its config.
+async fn resolve_from(
+    clients: &http::Clients,
+    source: Source,
+    reference: &reference::ImageReference,
+    options: &PullOptions,
+    platform: &platform::Platform,
+    cache: Option<&cache::BlobCache>,
+) -> Result<Resolved> {
+    let registry_url = source.url.as_str();
+    let repository = reference.repository.as_str();
+    // Only fall back to another platform if the user didn't ask for a specific one
+    let fallback = options.platform.is_none();
+
+    // The registry asks for a token when it wants one
+    let tokens = auth::TokenManager::new(
+        clients,
+        registry_url,
+        repository,
+        source.credentials.clone(),
+        retry::RetryPolicy {
+            max_retries: options.retries,
+            max_delay: Duration::from_secs(options.max_retry_delay),
+            max_rate_limit_wait: Duration::from_secs(options.max_rate_limit_wait),
+            ..Default::default()
+        },
+        (!options.no_token_cache)
+            .then(|| token_cache::TokenCache::new(&token_cache::TokenCache::default_path())),
+    );
+
+    // A pinned digest may still point at a manifest list
+    let image = registry::resolve_image_manifest(
+        clients,
+        registry_url,
+        repository,
+        reference.reference(),
+        &tokens,
+        platform,
+        fallback,
+    )
+    .await?;
+    let config = registry::get_image_config(
+        clients,
+        registry_url,
+        repository,
+        &tokens,
+        &image.manifest.config,
+        cache,
+    )
+    .await?;
+
+    Ok(Resolved {
+        source,
+        tokens,
+        image,
+        config,
+    })
+}
+
+/// Pulls the image from `source` and unpacks it into `temp_dir_path`.
+async fn pull_from(
+    clients: &http::Clients,
+    source: Source,
+    reference: &reference::ImageReference,
+    options: &PullOptions,
+    platform: &platform::Platform,
+    temp_dir_path: &Path,
+    cache: Option<&cache::BlobCache>,
+) -> Result<Pulled> {
+    let Resolved {
+        source,
+        tokens,
+        mut image,
+        config,
+    } = resolve_from(clients, source, reference, options, platform, cache).await?;
+    if options.skip_foreign_layers {
+        image.manifest.layers.retain(|layer| {
+            if layer.is_foreign() {
+                tracing::warn!("Skipping foreign layer {}", layer.digest);
+            }
+            !layer.is_foreign()
+        });
+    }
+    let layers = image
+        .manifest
+        .layers
+        .iter()
+        .map(|layer| layer.digest.clone())
+        .collect();
+
+    // Download each layer and unpack it to the temp dir
+    registry::download_layers(
+        clients,
+        &source.url,
+        &reference.repository,
+        &tokens,
+        image.manifest.layers,
+        temp_dir_path,
+        &registry::DownloadOptions {
+            max_concurrent_downloads: options.max_concurrent_downloads,
+            cache,
+            quiet: options.quiet,
+            read_timeout: Duration::from_secs(options.read_timeout),
+            max_unpacked_size: options.max_unpacked_size,
+            applied: options.applied_layers.as_deref(),
+        },
+    )
+    .await?;
+
+    Ok(Pulled {
+        digest: image.digest,
+        platform: image.platform,
+        config_digest: image.manifest.config.digest,
+        layers,
+        config,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use wiremock::MockServer;
+
+    /// Options that keep tests away from the user's cache, token cache and store.
+    fn isolated() -> PullOptions {
+        PullOptions {
+            no_cache: true,
+            no_token_cache: true,
+            quiet: true,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn pulls_from_a_plain_http_registry() {
+        let server = MockServer::start().await;
+        let layer = testing::layer(&[("hello", "world")]);
+        testing::serve_image(
+            &server,
+            &[layer],
+            serde_json::json!({"config": {"Cmd": ["/hello"]}}),
+        )
+        .await;
+        let image = format!("{}/{}", server.address(), testing::REPOSITORY);
+
+        let info = inspect_image(&image, &isolated()).await.unwrap();
+
+        assert_eq!(info.layers.len(), 1);
+        assert_eq!(info.config.cmd, Some(vec!["/hello".to_owned()]));
+    }
+
+    #[tokio::test]
+    async fn the_printed_config_has_every_key_whatever_the_image_sets() {
+        let server = MockServer::start().await;
+        let layer = testing::layer(&[("hello", "world")]);
+        testing::serve_image(
+            &server,
+            std::slice::from_ref(&layer),
+            serde_json::json!({"config": {"Cmd": ["/hello"]}}),
+        )
+        .await;
+        let image = format!("{}/{}", server.address(), testing::REPOSITORY);
+
+        let info = inspect_image(&image, &isolated()).await.unwrap();
+        let json = serde_json::to_value(&info).unwrap();
+
+        let mut keys: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                "Cmd",
+                "Digest",
+                "Entrypoint",
+                "Env",
+                "ExposedPorts",
+                "Layers",
+                "Platform",
+                "User",
+                "WorkingDir"
+            ]
+        );
+        assert_eq!(json["Cmd"], serde_json::json!(["/hello"]));
+        assert_eq!(json["Entrypoint"], serde_json::Value::Null);
+        assert!(json["Digest"].as_str().unwrap().starts_with("sha256:"));
+        assert_eq!(
+            json["Layers"],
+            serde_json::json!([{
+                "Digest": crate::digest::sha256_digest(&layer),
+                "Size": layer.len(),
+                "MediaType": testing::GZIP_LAYER,
+            }])
+        );
+    }
+
+    #[test]
+    fn talks_plain_http_only_to_local_and_insecure_registries() {
+        let url = |image: &str, insecure: &[&str]| {
+            let insecure: Vec<String> = insecure.iter().map(|host| host.to_string()).collect();
+            reference::parse_image_reference(image)
+                .unwrap()
+                .registry_url(&insecure)
+        };
+        assert_eq!(url("localhost:5000/img", &[]), "http://localhost:5000");
+        assert_eq!(url("127.0.0.1:5000/img", &[]), "http://127.0.0.1:5000");
+        assert_eq!(url("registry.test/img", &[]), "https://registry.test");
+        assert_eq!(
+            url("registry.test:5000/img", &["registry.test"]),
+            "http://registry.test:5000"
+        );
+        assert_eq!(url("alpine", &[]), "https://registry.hub.docker.com");
+    }
+
+    fn urls(image: &str, options: &PullOptions) -> Vec<String> {
+        let reference = reference::parse_image_reference(image).unwrap();
+        let sources = sources(&reference, options).unwrap();
+        sources.into_iter().map(|source| source.url).collect()
+    }
+
+    #[test]
+    fn docker_hub_falls_back_from_its_mirrors_only_when_asked_to() {
+        let mut options = PullOptions {
+            registry_mirror: vec!["mirror.test/".to_owned(), "http://other.test".to_owned()],
+            ..isolated()
+        };
+        assert_eq!(
+            urls("alpine", &options),
+            ["https://mirror.test", "http://other.test"]
+        );
+        // Mirrors are only for Docker Hub
+        assert_eq!(urls("quay.io/img", &options), ["https://quay.io"]);
+
+        options.mirror_fallback = true;
+        assert_eq!(
+            urls("alpine", &options),
+            [
+                "https://mirror.test",
+                "http://other.test",
+                "https://registry.hub.docker.com"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn docker_hub_images_are_pulled_from_the_mirror() {
+        let (broken, mirror) = (MockServer::start().await, MockServer::start().await);
+        let layer = testing::layer(&[("hello", "world")]);
+        testing::serve_image(&mirror, &[layer], serde_json::json!({})).await;
+        let options = PullOptions {
+            registry_mirror: vec![broken.uri(), mirror.uri()],
+            ..isolated()
+        };
+
+        let info = inspect_image(testing::REPOSITORY, &options).await.unwrap();
+
+        assert_eq!(info.layers.len(), 1);
+        let requests = broken.received_requests().await.unwrap();
+        assert_eq!(requests[0].url.path(), "/v2/test/img/manifests/latest");
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::{bail, Result};
+
+/// Registry used when the reference doesn't name one.
+pub const DEFAULT_REGISTRY: &str = "registry.hub.docker.com";
+
+/// A parsed image reference such as `ubuntu`, `ghcr.io/user/repo:tag` or `ubuntu@sha256:...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageReference {
+    /// Registry host, optionally with a port
+    pub registry: String,
+    /// Full repository path on the registry, e.g. `library/ubuntu`
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl ImageReference {
+    /// Base URL of the registry API, without the `/v2/` suffix. Registries in `insecure`, by
+    /// host or `host:port`, are spoken to over plain HTTP, and so is the local host like Docker
+    /// does for a registry run next to it.
+    pub fn registry_url(&self, insecure: &[String]) -> String {
+        let host = self.host();
+        let is_insecure =
+            host == "localhost" || host.starts_with("127.") || self.registry_in(insecure);
+
+        match is_insecure {
+            true => format!("http://{}", self.registry),
+            false => format!("https://{}", self.registry),
+        }
+    }
+
+    /// The registry without its port.
+    fn host(&self) -> &str {
+        self.registry
+            .rsplit_once(':')
+            .map_or(self.registry.as_str(), |(host, _)| host)
+    }
+
+    /// Whether the registry is in a list of registries given as `host` or `host:port`.
+    pub fn registry_in(&self, registries: &[String]) -> bool {
+        registries
+            .iter()
+            .any(|registry| registry == &self.registry || registry == self.host())
+    }
+
+    /// The tag or digest to ask the registry for, preferring the digest when both are given.
+    pub fn reference(&self) -> &str {
+        self.digest
+            .as_deref()
+            .or(self.tag.as_deref())
+            .unwrap_or("latest")
+    }
+}
+
+pub fn parse_image_reference(image_name: &str) -> Result<ImageReference> {
+    let (name, digest) = match image_name.split_once('@') {
+        Some((name, digest)) => {
+            let valid = match digest.split_once(':') {
+                Some(("sha256" | "sha512", hex)) => {
+                    !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit())
+                }
+                _ => false,
+            };
+            if !valid {
+                bail!(
+                    "Invalid digest '{}' in image reference '{}'",
+                    digest,
+                    image_name
+                );
+            }
+            (name, Some(digest.to_owned()))
+        }
+        None => (image_name, None),
+    };
+
+    // A ':' after the last '/' separates the tag, anything before could be a registry port
+    let (repository, tag) = match name.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => (repository, Some(tag.to_owned())),
+        _ => (name, None),
+    };
+
+    if repository.is_empty() {
+        bail!("Image reference '{}' is missing a repository", image_name);
+    }
+    if tag.as_deref() == Some("") {
+        bail!("Image reference '{}' has an empty tag", image_name);
+    }
+
+    let (registry, repository) = split_registry(repository);
+    if repository.is_empty() {
+        bail!("Image reference '{}' is missing a repository", image_name);
+    }
+    if repository.split('/').any(str::is_empty) {
+        bail!(
+            "Image reference '{}' has an empty path component in repository '{}'",
+            image_name,
+            repository
+        );
+    }
+
+    Ok(ImageReference {
+        registry,
+        repository,
+        tag,
+        digest,
+    })
+}
+
+/// Splits off the registry host, which is only present if the first path component looks like
+/// one.
+fn split_registry(name: &str) -> (String, String) {
+    let (registry, path) = match name.split_once('/') {
+        Some((host, path)) if host.contains('.') || host.contains(':') || host == "localhost" => {
+            (host, path)
+        }
+        _ => (DEFAULT_REGISTRY, name),
+    };
+
+    let registry = match registry {
+        "docker.io" | "index.docker.io" => DEFAULT_REGISTRY,
+        registry => registry,
+    };
+
+    (registry.to_owned(), normalize_repository(registry, path))
+}
+
+/// Docker Hub keeps official images under `library/`, so `alpine` really means `library/alpine`.
+/// Anything with a namespace (`grafana/grafana`) or on another registry is used as is.
+fn normalize_repository(registry: &str, path: &str) -> String {
+    if registry == DEFAULT_REGISTRY && !path.contains('/') {
+        format!("library/{}", path)
+    } else {
+        path.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repository(image_name: &str) -> String {
+        parse_image_reference(image_name).unwrap().repository
+    }
+
+    #[test]
+    fn docker_hub_namespaces_are_kept_and_official_images_get_library() {
+        assert_eq!(repository("alpine"), "library/alpine");
+        assert_eq!(repository("alpine:3.19"), "library/alpine");
+        assert_eq!(repository("foo/bar"), "foo/bar");
+        assert_eq!(repository("a/b/c"), "a/b/c");
+        assert_eq!(repository("library/alpine"), "library/alpine");
+
+        let reference = parse_image_reference("foo/bar:1.0").unwrap();
+        assert_eq!(reference.registry, DEFAULT_REGISTRY);
+        assert_eq!(reference.tag.as_deref(), Some("1.0"));
+    }
+
+    #[test]
+    fn user_repositories_on_other_registries_are_taken_as_they_are() {
+        let reference = parse_image_reference("ghcr.io/user/repo:v1").unwrap();
+        assert_eq!(reference.registry, "ghcr.io");
+        assert_eq!(reference.repository, "user/repo");
+        assert_eq!(reference.reference(), "v1");
+
+        // Not even a lone name gets `library/` off Docker Hub
+        let reference = parse_image_reference("localhost:5000/repo").unwrap();
+        assert_eq!(reference.registry, "localhost:5000");
+        assert_eq!(reference.repository, "repo");
+        assert_eq!(reference.reference(), "latest");
+
+        let reference = parse_image_reference("docker.io/user/repo").unwrap();
+        assert_eq!(reference.registry, DEFAULT_REGISTRY);
+        assert_eq!(reference.repository, "user/repo");
+
+        assert!(parse_image_reference("user//repo").is_err());
+    }
+}
@@ -0,0 +1,1564 @@
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use futures::{stream, StreamExt, TryStreamExt};
+use reqwest::{
+    header::{ACCEPT, CONTENT_RANGE, CONTENT_TYPE, RANGE},
+    StatusCode,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tempfile::NamedTempFile;
+use tokio::sync::mpsc;
+
+use crate::{
+    auth::TokenManager,
+    cache::BlobCache,
+    config::ImageConfig,
+    digest::{self, DigestVerifier},
+    error::PullError,
+    http,
+    layer::{unpack_layer, AppliedLayers, Compression, SizeBudget},
+    platform::Platform,
+    progress::{LayerProgress, Progress},
+    retry,
+};
+
+#[derive(serde::Deserialize, Debug)]
+struct DistributionManifestResponse {
+    manifests: Vec<DistributionManifest>,
+}
+
+/// An entry of a Docker manifest list or OCI image index. The two differ in the media types of
+/// their entries, and the OCI index makes `platform` optional.
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DistributionManifest {
+    media_type: Option<String>,
+    artifact_type: Option<String>,
+    digest: String,
+    size: Option<u64>,
+    platform: Option<Platform>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+impl DistributionManifest {
+    /// Buildkit stores provenance and SBOM attestations in the index next to the images, as
+    /// `unknown/unknown` entries that don't contain a filesystem.
+    fn is_attestation(&self) -> bool {
+        let reference_type = self.annotations.get("vnd.docker.reference.type");
+        let unknown_platform = self
+            .platform
+            .as_ref()
+            .is_some_and(|p| p.os == "unknown" && p.architecture == "unknown");
+
+        reference_type.is_some_and(|t| t == "attestation-manifest")
+            || self
+                .artifact_type
+                .as_deref()
+                .is_some_and(|t| t.starts_with("application/vnd.in-toto"))
+            || unknown_platform
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct ImageManifestResponse {
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+/// A reference to a blob, such as a layer or the image config.
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: Option<String>,
+    pub digest: String,
+    #[serde(default)]
+    pub size: u64,
+    /// Where a foreign layer can be downloaded from, instead of or besides the registry
+    #[serde(default)]
+    pub urls: Vec<String>,
+}
+
+/// Layers that, for licensing reasons, registries traditionally don't distribute themselves.
+/// Windows base layers mostly.
+const FOREIGN_LAYER_MEDIA_TYPES: [&str; 4] = [
+    "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip",
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd",
+];
+
+impl Descriptor {
+    pub fn is_foreign(&self) -> bool {
+        self.media_type
+            .as_deref()
+            .is_some_and(|media_type| FOREIGN_LAYER_MEDIA_TYPES.contains(&media_type))
+    }
+}
+
+const MANIFEST_LIST_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.oci.image.index.v1+json",
+];
+const IMAGE_MANIFEST_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.docker.distribution.manifest.v2+json",
+    "application/vnd.oci.image.manifest.v1+json",
+];
+
+/// What a tag (or digest) points at: either a list of per-platform images, already narrowed
+/// down to the digest of ours, or a single platform image.
+pub enum TagManifest {
+    Digest {
+        digest: String,
+        platform: Platform,
+    },
+    Image {
+        digest: String,
+        manifest: ImageManifestResponse,
+    },
+}
+
+/// The image manifest a reference resolved to, along with its digest and platform.
+#[derive(Debug)]
+pub struct ResolvedImage {
+    pub digest: String,
+    pub platform: Platform,
+    pub manifest: ImageManifestResponse,
+}
+
+/// Fetches the manifest at `reference` and checks it against the digest it is addressed by, or
+/// the one the registry reports for it. Returns the body with its media type and digest.
+async fn get_manifest(
+    clients: &http::Clients,
+    registry_url: &str,
+    repository: &str,
+    reference: &str,
+    tokens: &TokenManager,
+    accept: &str,
+) -> Result<(String, String, Bytes), anyhow::Error> {
+    let url = format!(
+        "{registry_url}/v2/{repository}/manifests/{reference}",
+        registry_url = registry_url,
+        repository = repository,
+        reference = reference
+    );
+    tracing::debug!("Fetching manifest {}", url);
+    let response = tokens
+        .send(|| clients.get(&url).header(ACCEPT, accept))
+        .await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Err(PullError::ManifestNotFound {
+            repository: repository.to_owned(),
+            reference: reference.to_owned(),
+        }
+        .into());
+    }
+    let response = response.error_for_status()?;
+
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    let media_type = header(CONTENT_TYPE.as_str()).unwrap_or_default();
+    let expected_digest = if reference.contains(':') {
+        Some(reference.to_owned())
+    } else {
+        header("docker-content-digest")
+    };
+    let manifest_data = response.bytes().await?;
+
+    // The digest is all we trust, so make sure the registry served what it names
+    let manifest_digest = match expected_digest {
+        Some(expected_digest) => {
+            digest::verify_digest(&expected_digest, &manifest_data)
+                .with_context(|| format!("Manifest {} failed verification", reference))?;
+            expected_digest
+        }
+        None => digest::sha256_digest(&manifest_data),
+    };
+
+    // Strip parameters like `; charset=utf-8`
+    let media_type = media_type.split(';').next().unwrap_or("").trim().to_owned();
+    Ok((media_type, manifest_digest, manifest_data))
+}
+
+/// Resolves a tag or digest to the image built for `platform`. With `fallback` set, an image
+/// list without a match falls back to its first entry instead of failing.
+pub async fn get_image_digest(
+    clients: &http::Clients,
+    registry_url: &str,
+    repository: &str,
+    tag: &str,
+    tokens: &TokenManager,
+    platform: &Platform,
+    fallback: bool,
+) -> Result<TagManifest, PullError> {
+    let accept = MANIFEST_LIST_MEDIA_TYPES
+        .iter()
+        .chain(IMAGE_MANIFEST_MEDIA_TYPES.iter())
+        .copied()
+        .collect::<Vec<_>>()
+        .join(", ");
+    let (media_type, manifest_digest, manifest_data) =
+        get_manifest(clients, registry_url, repository, tag, tokens, &accept).await?;
+    let image = |manifest| TagManifest::Image {
+        digest: manifest_digest.clone(),
+        manifest,
+    };
+
+    // Single platform images are served as the image manifest itself
+    let manifest: DistributionManifestResponse = match media_type.as_str() {
+        media_type if IMAGE_MANIFEST_MEDIA_TYPES.contains(&media_type) => {
+            return Ok(image(serde_json::from_slice(&manifest_data)?));
+        }
+        media_type if MANIFEST_LIST_MEDIA_TYPES.contains(&media_type) => {
+            serde_json::from_slice(&manifest_data)?
+        }
+        // Registries that don't label what they send get the benefit of the doubt
+        "" | "application/json" => {
+            let value: serde_json::Value = serde_json::from_slice(&manifest_data)?;
+            if value.get("manifests").is_none() {
+                return Ok(image(serde_json::from_value(value)?));
+            }
+            serde_json::from_value(value)?
+        }
+        media_type => {
+            return Err(anyhow!("Unsupported manifest type '{}' for {}", media_type, tag).into())
+        }
+    };
+
+    // Only entries describing a runnable image are of interest, attestations, nested indexes and
+    // entries without a platform aren't
+    let images: Vec<_> = manifest
+        .manifests
+        .iter()
+        .filter(|m| !m.is_attestation())
+        .filter(|m| {
+            m.media_type
+                .as_deref()
+                .is_none_or(|media_type| IMAGE_MANIFEST_MEDIA_TYPES.contains(&media_type))
+        })
+        .filter_map(|m| m.platform.as_ref().map(|platform| (platform, m)))
+        .collect();
+
+    // Lists can carry entries for other systems as well as `unknown/unknown` attestations,
+    // neither of which we could ever run
+    let candidates: Vec<_> = images
+        .iter()
+        .filter(|(candidate, _)| candidate.os == platform.os)
+        .collect();
+
+    // Prefer an exact match so a declared variant wins over a generic build
+    if let Some((candidate, m)) = candidates
+        .iter()
+        .find(|(candidate, _)| *candidate == platform)
+        .or_else(|| {
+            candidates
+                .iter()
+                .find(|(candidate, _)| platform.matches(candidate))
+        })
+    {
+        tracing::debug!(
+            "Selected manifest {} ({} bytes)",
+            m.digest,
+            m.size.unwrap_or_default()
+        );
+        return Ok(TagManifest::Digest {
+            digest: m.digest.to_owned(),
+            platform: (*candidate).clone(),
+        });
+    }
+
+    match candidates.first() {
+        Some((candidate, m)) if fallback => {
+            tracing::warn!(
+                "No manifest found for {}, using {} instead",
+                platform,
+                candidate
+            );
+            Ok(TagManifest::Digest {
+                digest: m.digest.to_owned(),
+                platform: (*candidate).clone(),
+            })
+        }
+        _ => {
+            let available: Vec<_> = images
+                .iter()
+                .map(|(candidate, _)| candidate.to_string())
+                .collect();
+            Err(PullError::PlatformUnavailable {
+                platform: platform.to_string(),
+                available,
+            })
+        }
+    }
+}
+
+async fn get_image_manifest(
+    clients: &http::Clients,
+    registry_url: &str,
+    repository: &str,
+    image_digest: &str,
+    tokens: &TokenManager,
+) -> Result<ImageManifestResponse, anyhow::Error> {
+    let accept = IMAGE_MANIFEST_MEDIA_TYPES.join(", ");
+    let (_, _, manifest_data) = get_manifest(
+        clients,
+        registry_url,
+        repository,
+        image_digest,
+        tokens,
+        &accept,
+    )
+    .await?;
+
+    Ok(serde_json::from_slice(&manifest_data)?)
+}
+
+/// Resolves a tag or digest all the way to the image manifest for `platform`, whether it points
+/// at a manifest list or directly at a single platform image.
+pub async fn resolve_image_manifest(
+    clients: &http::Clients,
+    registry_url: &str,
+    repository: &str,
+    reference: &str,
+    tokens: &TokenManager,
+    platform: &Platform,
+    fallback: bool,
+) -> Result<ResolvedImage, PullError> {
+    match get_image_digest(
+        clients,
+        registry_url,
+        repository,
+        reference,
+        tokens,
+        platform,
+        fallback,
+    )
+    .await?
+    {
+        // A single platform image doesn't say what it was built for, trust that it fits
+        TagManifest::Image { digest, manifest } => Ok(ResolvedImage {
+            digest,
+            platform: platform.clone(),
+            manifest,
+        }),
+        TagManifest::Digest { digest, platform } => {
+            let manifest =
+                get_image_manifest(clients, registry_url, repository, &digest, tokens).await?;
+            Ok(ResolvedImage {
+                digest,
+                platform,
+                manifest,
+            })
+        }
+    }
+}
+
+/// Fetches and parses the image config blob, which holds the defaults for running the image.
+/// It is kept in the cache along with the layers.
+pub async fn get_image_config(
+    clients: &http::Clients,
+    registry_url: &str,
+    repository: &str,
+    tokens: &TokenManager,
+    config: &Descriptor,
+    cache: Option<&BlobCache>,
+) -> Result<ImageConfig, PullError> {
+    let digest = config.digest.as_str();
+    if let Some(path) = cache.and_then(|cache| cache.get(digest)) {
+        return read_image_config(&path);
+    }
+
+    let response = tokens
+        .send(|| {
+            clients.get(&format!(
+                "{registry_url}/v2/{repository}/blobs/{digest}",
+                registry_url = registry_url,
+                repository = repository,
+                digest = digest
+            ))
+        })
+        .await?
+        .error_for_status()
+        .with_context(|| format!("Failed to download image config {}", digest))?;
+    let data = response.bytes().await?;
+    digest::verify_digest(digest, &data)
+        .with_context(|| format!("Image config {} failed verification", digest))?;
+
+    if let Some(cache) = cache {
+        let mut temp_file = cache.temp_file()?;
+        temp_file.write_all(&data)?;
+        cache.persist(digest, temp_file)?;
+    }
+
+    Ok(
+        serde_json::from_slice(&data)
+            .with_context(|| format!("Invalid image config {}", digest))?,
+    )
+}
+
+/// Parses an image config blob we already have.
+pub fn read_image_config(path: &Path) -> Result<ImageConfig, PullError> {
+    let data = std::fs::read(path)?;
+    Ok(serde_json::from_slice(&data)
+        .with_context(|| format!("Invalid image config {}", path.display()))?)
+}
+
+/// Where a downloaded layer ended up.
+enum LayerBlob {
+    Cached(PathBuf),
+    /// Without a cache the blob only lives until it has been unpacked
+    Spooled(NamedTempFile),
+}
+
+/// Where a blob comes from: the registry, which gets our token, or a foreign layer's URL, which
+/// doesn't. Both go by the registry's retry policy.
+#[derive(Clone, Copy)]
+enum BlobHost<'a> {
+    Registry(&'a TokenManager),
+    Foreign(&'a TokenManager),
+}
+
+impl BlobHost<'_> {
+    fn retry_policy(&self) -> &retry::RetryPolicy {
+        match self {
+            BlobHost::Registry(tokens) | BlobHost::Foreign(tokens) => tokens.retry_policy(),
+        }
+    }
+}
+
+/// Downloads a blob into `file`, keeping whatever prefix it already holds and asking only for
+/// the rest with a Range request. Content is hashed on the way, a truncated or corrupted blob
+/// must never get unpacked.
+async fn download_blob(
+    clients: &http::Clients,
+    host: BlobHost<'_>,
+    url: &str,
+    digest: &str,
+    file: &mut File,
+    progress: &LayerProgress,
+    read_timeout: Duration,
+) -> Result<(), anyhow::Error> {
+    let mut attempt = 0;
+    loop {
+        let mut verifier = DigestVerifier::new(digest)?;
+        let offset = file.seek(SeekFrom::End(0))?;
+        if offset > 0 {
+            tracing::debug!("Resuming {} at byte {}", digest, offset);
+            file.seek(SeekFrom::Start(0))?;
+            let mut prefix = (&mut *file).take(offset);
+            std::io::copy(&mut prefix, &mut VerifierWriter(&mut verifier))?;
+        }
+
+        let build = || {
+            // Blobs take as long as they take, the read timeout catches stalled ones
+            let request = clients.get(url).timeout(http::NO_DEADLINE);
+            match offset {
+                0 => request,
+                offset => request.header(RANGE, format!("bytes={}-", offset)),
+            }
+        };
+        let response = match host {
+            BlobHost::Registry(tokens) => tokens.send(build).await?,
+            BlobHost::Foreign(_) => retry::send_with_retry(host.retry_policy(), build).await?,
+        };
+
+        // Blobs are often redirected to a CDN, and with mirrors it's good to know which one
+        tracing::info!(
+            "{} served by {}",
+            digest,
+            response.url().host_str().unwrap_or("?")
+        );
+        let mut response = match response.status() {
+            StatusCode::PARTIAL_CONTENT if range_start(&response) == Some(offset) => {
+                progress.start(offset, response.content_length());
+                response
+            }
+            // Appending a range we didn't ask for would only corrupt the blob
+            StatusCode::PARTIAL_CONTENT => {
+                tracing::warn!(
+                    "{} answered the range request with the wrong range, starting over",
+                    url
+                );
+                file.set_len(0)?;
+                file.seek(SeekFrom::Start(0))?;
+                continue;
+            }
+            // We already have every byte, whether they are the right ones is up to the verifier
+            StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => return verifier.finish(),
+            status if status.is_success() => {
+                // The registry ignored the range and sends everything, so start over
+                if offset > 0 {
+                    tracing::debug!("{} ignored the range request", url);
+                    file.set_len(0)?;
+                    file.seek(SeekFrom::Start(0))?;
+                    verifier = DigestVerifier::new(digest)?;
+                }
+                progress.start(0, response.content_length());
+                response
+            }
+            _ => response.error_for_status()?,
+        };
+
+        let mut body_result = Ok(());
+        loop {
+            match tokio::time::timeout(read_timeout, response.chunk()).await {
+                Ok(Ok(Some(chunk))) => {
+                    verifier.update(&chunk);
+                    file.write_all(&chunk)?;
+                    progress.inc(chunk.len() as u64);
+                }
+                Ok(Ok(None)) => break,
+                Ok(Err(err)) => {
+                    body_result = Err(anyhow::Error::from(err));
+                    break;
+                }
+                Err(_) => {
+                    body_result = Err(anyhow!(
+                        "Reading {} timed out after {:?} without data",
+                        url,
+                        read_timeout
+                    ));
+                    break;
+                }
+            }
+        }
+
+        match body_result {
+            Ok(()) => {
+                verifier.finish()?;
+                progress.finish();
+                return Ok(());
+            }
+            // Resuming picks up the hash from what is already on disk, so this stays verified
+            Err(err) if attempt < host.retry_policy().max_retries => {
+                let delay = host.retry_policy().backoff(attempt);
+                attempt += 1;
+                tracing::warn!(
+                    "Download of {} broke off (attempt {}/{}), resuming in {:?}: {}",
+                    digest,
+                    attempt,
+                    host.retry_policy().max_retries + 1,
+                    delay,
+                    err
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Where the body of a partial response starts, from `Content-Range: bytes <start>-<end>/<size>`.
+fn range_start(response: &reqwest::Response) -> Option<u64> {
+    let range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+fn is_digest_mismatch(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<PullError>(),
+        Some(PullError::DigestMismatch { .. })
+    )
+}
+
+/// Lets `std::io::copy` feed a file into a `DigestVerifier`.
+struct VerifierWriter<'a>(&'a mut DigestVerifier);
+
+impl Write for VerifierWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Downloads a layer blob into `file`. A foreign layer is tried from its own URLs first, and
+/// only then from the registry, at `registry_blob_url`. Those URLs are someone else's, they
+/// don't get our token.
+async fn fetch_layer(
+    clients: &http::Clients,
+    tokens: &TokenManager,
+    layer: &Descriptor,
+    registry_blob_url: &str,
+    file: &mut File,
+    progress: &LayerProgress,
+    read_timeout: Duration,
+) -> Result<(), anyhow::Error> {
+    let digest = layer.digest.as_str();
+    if layer.is_foreign() {
+        let urls = layer
+            .urls
+            .iter()
+            .filter(|url| url.starts_with("https://") || url.starts_with("http://"));
+        for url in urls {
+            match download_blob(
+                clients,
+                BlobHost::Foreign(tokens),
+                url,
+                digest,
+                file,
+                progress,
+                read_timeout,
+            )
+            .await
+            {
+                Ok(()) => return Ok(()),
+                // A server that sent the wrong bytes has them in the file now
+                Err(err) if is_digest_mismatch(&err) => {
+                    tracing::warn!("{}: {:#}", url, err);
+                    file.set_len(0)?;
+                    file.seek(SeekFrom::Start(0))?;
+                }
+                Err(err) => tracing::warn!("Failed to download {} from {}: {:#}", digest, url, err),
+            }
+        }
+    }
+
+    download_blob(
+        clients,
+        BlobHost::Registry(tokens),
+        registry_blob_url,
+        digest,
+        file,
+        progress,
+        read_timeout,
+    )
+    .await
+}
+
+async fn download_layer(
+    clients: &http::Clients,
+    registry_url: &str,
+    repository: &str,
+    tokens: &TokenManager,
+    layer: &Descriptor,
+    options: &DownloadOptions<'_>,
+    progress: &Progress,
+) -> Result<LayerBlob, anyhow::Error> {
+    let digest = layer.digest.as_str();
+    let context = || format!("Failed to download layer {}", digest);
+    let url = format!(
+        "{registry_url}/v2/{repository}/blobs/{digest}",
+        registry_url = registry_url,
+        repository = repository,
+        digest = digest
+    );
+
+    if let Some(cache) = options.cache {
+        if let Some(path) = cache.get(digest) {
+            tracing::info!("Using cached layer {}", digest);
+            cache.touch(digest);
+            progress.skip(layer.size);
+            return Ok(LayerBlob::Cached(path));
+        }
+    }
+    let progress = progress.layer(digest, layer.size);
+
+    let cache = match options.cache {
+        Some(cache) => cache,
+        // Stream to disk so memory use doesn't grow with the layer size
+        None => {
+            let mut temp_file = NamedTempFile::new()?;
+            fetch_layer(
+                clients,
+                tokens,
+                layer,
+                &url,
+                temp_file.as_file_mut(),
+                &progress,
+                options.read_timeout,
+            )
+            .await
+            .with_context(context)?;
+            return Ok(LayerBlob::Spooled(temp_file));
+        }
+    };
+
+    match cache.partial(digest)? {
+        Some(mut partial) => {
+            let mut resumed = partial.file.metadata()?.len() > 0;
+            let result = loop {
+                let result = fetch_layer(
+                    clients,
+                    tokens,
+                    layer,
+                    &url,
+                    &mut partial.file,
+                    &progress,
+                    options.read_timeout,
+                )
+                .await;
+
+                if resumed && result.as_ref().is_err_and(is_digest_mismatch) {
+                    // What an earlier pull left behind was bad, give it one fresh try
+                    tracing::warn!("Discarding corrupt partial download of {}", digest);
+                    partial.file.set_len(0)?;
+                    partial.file.seek(SeekFrom::Start(0))?;
+                    resumed = false;
+                    continue;
+                }
+                break result;
+            };
+
+            match result {
+                Ok(()) => Ok(LayerBlob::Cached(cache.complete(digest, partial)?)),
+                Err(err) => {
+                    // Keep interrupted downloads around for the next pull, but not corrupt ones
+                    if is_digest_mismatch(&err) {
+                        cache.discard(partial)?;
+                    }
+                    Err(err).with_context(context)
+                }
+            }
+        }
+        // Someone else is downloading this blob already, don't fight over the partial file
+        None => {
+            let mut temp_file = cache.temp_file()?;
+            fetch_layer(
+                clients,
+                tokens,
+                layer,
+                &url,
+                temp_file.as_file_mut(),
+                &progress,
+                options.read_timeout,
+            )
+            .await
+            .with_context(context)?;
+            Ok(LayerBlob::Cached(cache.persist(digest, temp_file)?))
+        }
+    }
+}
+
+/// Knobs for how `download_layers` fetches blobs.
+pub struct DownloadOptions<'a> {
+    pub max_concurrent_downloads: usize,
+    /// Blobs are only fetched if they aren't in the cache yet
+    pub cache: Option<&'a BlobCache>,
+    /// No progress bars or progress lines at all
+    pub quiet: bool,
+    /// How long a blob download may go without receiving any data
+    pub read_timeout: Duration,
+    /// What all layers together may unpack to
+    pub max_unpacked_size: u64,
+    /// The record of the layers already unpacked into the root, if it is kept between pulls
+    pub applied: Option<&'a Path>,
+}
+
+/// Downloads up to `max_concurrent_downloads` layers at a time, but unpacks them strictly in
+/// manifest order since later layers overwrite earlier ones. The first failure drops the
+/// remaining downloads.
+pub async fn download_layers(
+    clients: &http::Clients,
+    registry_url: &str,
+    repository: &str,
+    tokens: &TokenManager,
+    mut layers: Vec<Descriptor>,
+    temp_dir_path: &Path,
+    options: &DownloadOptions<'_>,
+) -> Result<(), PullError> {
+    // Applying a layer right on top of itself changes nothing, which saves fetching and
+    // unpacking the empty layers older images repeat between their steps
+    let count = layers.len();
+    layers.dedup_by(|layer, previous| layer.digest == previous.digest);
+    if layers.len() < count {
+        tracing::debug!("Skipping {} repeated layers", count - layers.len());
+    }
+
+    // A root we unpacked into before may hold the first layers already, those aren't even
+    // downloaded again
+    let mut applied = match options.applied {
+        Some(path) => {
+            let digests: Vec<_> = layers.iter().map(|layer| layer.digest.clone()).collect();
+            let (applied, skip) = AppliedLayers::open(path, temp_dir_path, &digests)?;
+            if skip > 0 {
+                tracing::info!("Skipping {} layers that are unpacked already", skip);
+            }
+            layers.drain(..skip);
+            Some(applied)
+        }
+        None => None,
+    };
+
+    // Checked before downloading anything, not after the first few layers are in
+    let compressions = layers
+        .iter()
+        .map(|layer| {
+            layer
+                .media_type
+                .as_deref()
+                .map(Compression::from_media_type)
+                .transpose()
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let total = layers.iter().map(|layer| layer.size).sum();
+    tracing::info!(
+        "Image has {} layers, {} bytes in total",
+        layers.len(),
+        total
+    );
+    for layer in &layers {
+        tracing::debug!(
+            "Layer {}: {} bytes, {}",
+            layer.digest,
+            layer.size,
+            layer.media_type.as_deref().unwrap_or("no media type")
+        );
+    }
+    let progress = Progress::new(options.quiet, total);
+    let mut downloads = stream::iter(&layers)
+        .map(|layer| {
+            download_layer(
+                clients,
+                registry_url,
+                repository,
+                tokens,
+                layer,
+                options,
+                &progress,
+            )
+        })
+        .buffered(options.max_concurrent_downloads.max(1));
+
+    // Unpacking is blocking work, on a thread of its own it overlaps with the downloads still
+    // running rather than stalling them. Blobs reach it in the image's order, each once its
+    // download is complete.
+    let (sender, mut receiver) = mpsc::channel::<(String, Option<Compression>, LayerBlob)>(
+        options.max_concurrent_downloads.max(1),
+    );
+    let unpacker = {
+        let root = temp_dir_path.to_owned();
+        let mut budget = SizeBudget::new(options.max_unpacked_size);
+        tokio::task::spawn_blocking(move || -> Result<(), PullError> {
+            while let Some((digest, compression, layer_blob)) = receiver.blocking_recv() {
+                let path = match &layer_blob {
+                    LayerBlob::Cached(path) => path.as_path(),
+                    LayerBlob::Spooled(temp_file) => temp_file.path(),
+                };
+                unpack_layer(path, compression, &root, &mut budget).map_err(|err| {
+                    PullError::Extraction {
+                        digest: digest.clone(),
+                        source: err.into(),
+                    }
+                })?;
+                if let Some(applied) = &mut applied {
+                    applied.push(&digest)?;
+                }
+            }
+            Ok(())
+        })
+    };
+
+    let downloaded = async {
+        let mut unpacking = layers.iter().zip(compressions);
+        loop {
+            // Once the unpacker gives up there's no point in the downloads, its error says why
+            let layer_blob = tokio::select! {
+                layer_blob = downloads.try_next() => match layer_blob? {
+                    Some(layer_blob) => layer_blob,
+                    None => break,
+                },
+                _ = sender.closed() => break,
+            };
+            let Some((layer, compression)) = unpacking.next() else {
+                break;
+            };
+            let unpack = (layer.digest.clone(), compression, layer_blob);
+            if sender.send(unpack).await.is_err() {
+                break;
+            }
+        }
+        Ok::<_, PullError>(())
+    }
+    .await;
+    drop(downloads);
+    // Waited for even when a download failed, nothing may still be writing into the root once
+    // we return
+    drop(sender);
+    let unpacked = unpacker
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()));
+    progress.finish();
+
+    unpacked?;
+    downloaded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, REPOSITORY};
+    use wiremock::{
+        matchers::{header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    async fn pull_layers(
+        client: &http::Clients,
+        server: &MockServer,
+        tokens: &TokenManager,
+        layers: &[&[u8]],
+        options: &DownloadOptions<'_>,
+    ) -> Result<tempfile::TempDir, PullError> {
+        let root = tempfile::tempdir()?;
+        let layers = layers
+            .iter()
+            .map(|data| testing::descriptor(data))
+            .collect();
+        download_layers(
+            client,
+            &server.uri(),
+            REPOSITORY,
+            tokens,
+            layers,
+            root.path(),
+            options,
+        )
+        .await?;
+        Ok(root)
+    }
+
+    async fn requests(server: &MockServer) -> usize {
+        server.received_requests().await.unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn a_cached_layer_is_not_downloaded_again() {
+        let server = MockServer::start().await;
+        let layer = testing::layer(&[("hello", "world")]);
+        testing::serve_blob(&server, &layer).await;
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = BlobCache::new(cache_dir.path()).unwrap();
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+        let options = testing::download_options(Some(&cache));
+
+        pull_layers(&client, &server, &tokens, &[&layer], &options)
+            .await
+            .unwrap();
+        let before = requests(&server).await;
+        let root = pull_layers(&client, &server, &tokens, &[&layer], &options)
+            .await
+            .unwrap();
+
+        assert_eq!(requests(&server).await, before);
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("hello")).unwrap(),
+            "world"
+        );
+    }
+
+    #[tokio::test]
+    async fn layers_already_in_a_kept_root_are_skipped() {
+        let server = MockServer::start().await;
+        let (base, top) = (
+            testing::layer(&[("base", "")]),
+            testing::layer(&[("top", "")]),
+        );
+        testing::serve_blob(&server, &base).await;
+        testing::serve_blob(&server, &top).await;
+        let (root, dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let record = dir.path().join("layers.json");
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+        let options = DownloadOptions {
+            applied: Some(&record),
+            ..testing::download_options(None)
+        };
+        let uri = server.uri();
+        let pull = |layers: Vec<Descriptor>| {
+            download_layers(
+                &client,
+                &uri,
+                REPOSITORY,
+                &tokens,
+                layers,
+                root.path(),
+                &options,
+            )
+        };
+
+        // A pull that only got as far as the base layer, then one of the whole image
+        pull(vec![testing::descriptor(&base)]).await.unwrap();
+        std::fs::remove_file(root.path().join("base")).unwrap();
+        pull(vec![testing::descriptor(&base), testing::descriptor(&top)])
+            .await
+            .unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let paths: Vec<_> = requests.iter().map(|request| request.url.path()).collect();
+        assert_eq!(
+            paths,
+            [
+                testing::blob_path(&digest::sha256_digest(&base)),
+                testing::blob_path(&digest::sha256_digest(&top))
+            ]
+        );
+        // Not unpacked again either, or the file removed in between would be back
+        assert!(!root.path().join("base").exists());
+        assert!(root.path().join("top").exists());
+    }
+
+    #[tokio::test]
+    async fn a_rejected_token_is_refreshed() {
+        let server = MockServer::start().await;
+        let challenge = format!(r#"Bearer realm="{}/token",service="test""#, server.uri());
+        let unauthorized = ResponseTemplate::new(401).insert_header("WWW-Authenticate", &challenge);
+        // The second token is the first one's replacement, after it expired
+        Mock::given(path("/token"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({"token": "old"})),
+            )
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(path("/token"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({"token": "new"})),
+            )
+            .mount(&server)
+            .await;
+        let (first, second) = (testing::layer(&[("a", "")]), testing::layer(&[("b", "")]));
+        for (layer, token) in [(&first, "Bearer old"), (&second, "Bearer new")] {
+            Mock::given(path(testing::blob_path(&digest::sha256_digest(layer))))
+                .and(header("authorization", token))
+                .respond_with(ResponseTemplate::new(200).set_body_bytes(layer.clone()))
+                .with_priority(1)
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("GET"))
+            .respond_with(unauthorized)
+            .mount(&server)
+            .await;
+
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+        let options = DownloadOptions {
+            max_concurrent_downloads: 1,
+            ..testing::download_options(None)
+        };
+        let root = pull_layers(&client, &server, &tokens, &[&first, &second], &options)
+            .await
+            .unwrap();
+
+        assert!(root.path().join("a").exists() && root.path().join("b").exists());
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let server = MockServer::start().await;
+        let layer = testing::layer(&[("hello", "world")]);
+        Mock::given(path(testing::blob_path(&digest::sha256_digest(&layer))))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        testing::serve_blob(&server, &layer).await;
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+
+        pull_layers(
+            &client,
+            &server,
+            &tokens,
+            &[&layer],
+            &testing::download_options(None),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_given_up_on() {
+        let server = MockServer::start().await;
+        let layer = testing::layer(&[("hello", "world")]);
+        let blob = testing::blob_path(&digest::sha256_digest(&layer));
+        Mock::given(path(blob.as_str()))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+
+        let err = pull_layers(
+            &client,
+            &server,
+            &tokens,
+            &[&layer],
+            &testing::download_options(None),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(format!("{:#}", err).contains("503"), "{:#}", err);
+        let attempts = server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|request| request.url.path() == blob)
+            .count();
+        assert_eq!(attempts, testing::fast_retries().max_retries as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_are_waited_out() {
+        let server = MockServer::start().await;
+        let layer = testing::layer(&[("hello", "world")]);
+        Mock::given(path(testing::blob_path(&digest::sha256_digest(&layer))))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        testing::serve_blob(&server, &layer).await;
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+
+        pull_layers(
+            &client,
+            &server,
+            &tokens,
+            &[&layer],
+            &testing::download_options(None),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rate_limits_beyond_the_wait_fail_with_the_quota() {
+        let server = MockServer::start().await;
+        let layer = testing::layer(&[("hello", "world")]);
+        Mock::given(path(testing::blob_path(&digest::sha256_digest(&layer))))
+            .respond_with(
+                ResponseTemplate::new(429)
+                    .insert_header("Retry-After", "3600")
+                    .insert_header("ratelimit-remaining", "0;w=21600"),
+            )
+            .mount(&server)
+            .await;
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+
+        let err = pull_layers(
+            &client,
+            &server,
+            &tokens,
+            &[&layer],
+            &testing::download_options(None),
+        )
+        .await
+        .unwrap_err();
+
+        let message = format!("{:#}", err);
+        assert!(message.contains("Rate limited"), "{}", message);
+        assert!(
+            message.contains("ratelimit-remaining: 0;w=21600"),
+            "{}",
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn a_mismatched_blob_is_rejected_and_never_cached() {
+        let server = MockServer::start().await;
+        let layer = testing::layer(&[("hello", "world")]);
+        let digest = digest::sha256_digest(&layer);
+        Mock::given(path(testing::blob_path(&digest)))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_bytes(testing::layer(&[("evil", "")])),
+            )
+            .mount(&server)
+            .await;
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = BlobCache::new(cache_dir.path()).unwrap();
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+
+        let err = pull_layers(
+            &client,
+            &server,
+            &tokens,
+            &[&layer],
+            &testing::download_options(Some(&cache)),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(
+            matches!(&err, PullError::DigestMismatch { expected, .. } if *expected == digest),
+            "{:#}",
+            err
+        );
+        assert!(cache.get(&digest).is_none());
+        // Nor kept around to resume from
+        let partial = cache.partial(&digest).unwrap().unwrap();
+        assert_eq!(partial.file.metadata().unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn an_interrupted_download_resumes_with_a_range_request() {
+        let server = MockServer::start().await;
+        let layer = testing::layer(&[("hello", "world")]);
+        let digest = digest::sha256_digest(&layer);
+        let half = layer.len() / 2;
+        Mock::given(path(testing::blob_path(&digest)))
+            .and(header("range", format!("bytes={}-", half).as_str()))
+            .respond_with(
+                ResponseTemplate::new(206)
+                    .insert_header(
+                        "Content-Range",
+                        format!("bytes {}-{}/{}", half, layer.len() - 1, layer.len()).as_str(),
+                    )
+                    .set_body_bytes(&layer[half..]),
+            )
+            .mount(&server)
+            .await;
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache = BlobCache::new(cache_dir.path()).unwrap();
+        let mut partial = cache.partial(&digest).unwrap().unwrap();
+        partial.file.write_all(&layer[..half]).unwrap();
+        drop(partial);
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+
+        let root = pull_layers(
+            &client,
+            &server,
+            &tokens,
+            &[&layer],
+            &testing::download_options(Some(&cache)),
+        )
+        .await
+        .unwrap();
+
+        assert!(root.path().join("hello").exists());
+        assert_eq!(std::fs::read(cache.get(&digest).unwrap()).unwrap(), layer);
+    }
+
+    #[tokio::test]
+    async fn a_resume_answered_with_the_whole_blob_starts_over() {
+        let server = MockServer::start().await;
+        let layer = testing::layer(&[("hello", "world")]);
+        let digest = testing::serve_blob(&server, &layer).await;
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+        let progress = Progress::new(true, 0);
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&layer[..layer.len() / 2]).unwrap();
+
+        download_blob(
+            &client,
+            BlobHost::Registry(&tokens),
+            &format!("{}{}", server.uri(), testing::blob_path(&digest)),
+            &digest,
+            &mut file,
+            &progress.layer(&digest, layer.len() as u64),
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+
+        let mut downloaded = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut downloaded).unwrap();
+        assert_eq!(downloaded, layer);
+        let requests = server.received_requests().await.unwrap();
+        let range = &requests.last().unwrap().headers["range"];
+        assert_eq!(range, format!("bytes={}-", layer.len() / 2).as_str());
+    }
+
+    #[test]
+    fn digest_mismatches_are_recognized_through_context() {
+        let mismatch = digest::verify_digest(&digest::sha256_digest(b"a"), b"b").unwrap_err();
+        assert!(is_digest_mismatch(&mismatch.context("Failed to download")));
+        // Only by their type, not by what the message happens to say
+        assert!(!is_digest_mismatch(&anyhow!("Digest mismatch")));
+    }
+
+    async fn resolve(server: &MockServer, platform: &str) -> Result<ResolvedImage, PullError> {
+        let client = testing::clients();
+        resolve_image_manifest(
+            &client,
+            &server.uri(),
+            REPOSITORY,
+            "latest",
+            &testing::tokens(&client, server),
+            &platform.parse().unwrap(),
+            false,
+        )
+        .await
+    }
+
+    const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+
+    /// An image manifest without layers, told apart from others by `name`.
+    fn image_manifest(media_type: &str, name: &str) -> serde_json::Value {
+        serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": media_type,
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": digest::sha256_digest(name.as_bytes()),
+                "size": name.len(),
+            },
+            "layers": [],
+        })
+    }
+
+    /// Serves `manifest` as `media_type` at its digest, and at `tag` if there is one. Returns the
+    /// digest.
+    async fn serve_manifest(
+        server: &MockServer,
+        tag: Option<&str>,
+        media_type: &str,
+        manifest: &serde_json::Value,
+    ) -> String {
+        let body = serde_json::to_vec(manifest).unwrap();
+        let digest = digest::sha256_digest(&body);
+        for reference in [Some(digest.as_str()), tag].into_iter().flatten() {
+            Mock::given(path(format!("/v2/{}/manifests/{}", REPOSITORY, reference)))
+                .respond_with(ResponseTemplate::new(200).set_body_raw(body.clone(), media_type))
+                .mount(server)
+                .await;
+        }
+        digest
+    }
+
+    #[tokio::test]
+    async fn a_single_platform_image_is_its_own_manifest() {
+        let server = MockServer::start().await;
+        let manifest = image_manifest(OCI_MANIFEST, "image");
+        let digest = serve_manifest(&server, Some("latest"), OCI_MANIFEST, &manifest).await;
+
+        let resolved = resolve(&server, "linux/arm64/v8").await.unwrap();
+
+        assert_eq!(resolved.digest, digest);
+        assert_eq!(resolved.platform.to_string(), "linux/arm64/v8");
+        assert_eq!(
+            resolved.manifest.config.digest,
+            digest::sha256_digest(b"image")
+        );
+        assert_eq!(requests(&server).await, 1);
+    }
+
+    #[tokio::test]
+    async fn docker_manifest_lists_are_asked_for_and_followed() {
+        const DOCKER_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+        const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+        let server = MockServer::start().await;
+        let amd64 = image_manifest(DOCKER_MANIFEST, "amd64");
+        let amd64_digest = serve_manifest(&server, None, DOCKER_MANIFEST, &amd64).await;
+        let arm64 = image_manifest(DOCKER_MANIFEST, "arm64");
+        let arm64_digest = serve_manifest(&server, None, DOCKER_MANIFEST, &arm64).await;
+        let entry = |digest: &str, architecture: &str| {
+            serde_json::json!({
+                "mediaType": DOCKER_MANIFEST,
+                "digest": digest,
+                "size": 100,
+                "platform": {"os": "linux", "architecture": architecture},
+            })
+        };
+        let list = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": DOCKER_LIST,
+            "manifests": [entry(&arm64_digest, "arm64"), entry(&amd64_digest, "amd64")],
+        });
+        serve_manifest(&server, Some("latest"), DOCKER_LIST, &list).await;
+
+        let resolved = resolve(&server, "linux/amd64").await.unwrap();
+
+        assert_eq!(resolved.digest, amd64_digest);
+        assert_eq!(
+            resolved.manifest.config.digest,
+            digest::sha256_digest(b"amd64")
+        );
+        let requests = server.received_requests().await.unwrap();
+        let accept = requests[0].headers[ACCEPT.as_str()].to_str().unwrap();
+        assert!(accept.contains(DOCKER_LIST), "{}", accept);
+        assert!(
+            accept.contains("application/vnd.oci.image.index.v1+json"),
+            "{}",
+            accept
+        );
+    }
+
+    #[tokio::test]
+    async fn an_unlabelled_image_manifest_is_taken_for_a_single_platform_image() {
+        let server = MockServer::start().await;
+        let manifest = image_manifest(OCI_MANIFEST, "image");
+        let digest = serve_manifest(&server, Some("latest"), "application/json", &manifest).await;
+
+        let resolved = resolve(&server, "linux/amd64").await.unwrap();
+
+        assert_eq!(resolved.digest, digest);
+        assert_eq!(resolved.platform.to_string(), "linux/amd64");
+        assert_eq!(requests(&server).await, 1);
+    }
+
+    #[tokio::test]
+    async fn oci_indexes_are_narrowed_down_to_a_runnable_image() {
+        const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+        let server = MockServer::start().await;
+        let v7 = image_manifest(OCI_MANIFEST, "v7");
+        let v7_digest = serve_manifest(&server, None, OCI_MANIFEST, &v7).await;
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_INDEX,
+            "manifests": [
+                // Neither runnable nor ours, ahead of the one that is
+                {
+                    "mediaType": OCI_MANIFEST,
+                    "digest": digest::sha256_digest(b"attestation"),
+                    "size": 1,
+                    "platform": {"os": "unknown", "architecture": "unknown"},
+                    "annotations": {"vnd.docker.reference.type": "attestation-manifest"},
+                },
+                {"mediaType": OCI_MANIFEST, "digest": digest::sha256_digest(b"none"), "size": 1},
+                {
+                    "mediaType": OCI_INDEX,
+                    "digest": digest::sha256_digest(b"nested"),
+                    "size": 1,
+                    "platform": {"os": "linux", "architecture": "arm", "variant": "v7"},
+                },
+                {
+                    "mediaType": OCI_MANIFEST,
+                    "digest": digest::sha256_digest(b"v6"),
+                    "size": 1,
+                    "platform": {"os": "linux", "architecture": "arm", "variant": "v6"},
+                },
+                {
+                    "mediaType": OCI_MANIFEST,
+                    "digest": v7_digest,
+                    "size": 1,
+                    "platform": {"os": "linux", "architecture": "arm", "variant": "v7"},
+                },
+            ],
+        });
+        serve_manifest(&server, Some("latest"), OCI_INDEX, &index).await;
+
+        let resolved = resolve(&server, "linux/arm/v7").await.unwrap();
+
+        assert_eq!(resolved.digest, v7_digest);
+        assert_eq!(resolved.platform.to_string(), "linux/arm/v7");
+        assert_eq!(
+            resolved.manifest.config.digest,
+            digest::sha256_digest(b"v7")
+        );
+    }
+
+    #[tokio::test]
+    async fn a_missing_manifest_is_reported_as_such() {
+        let server = MockServer::start().await;
+
+        let err = resolve(&server, "linux/amd64").await.unwrap_err();
+
+        assert!(
+            matches!(&err, PullError::ManifestNotFound { reference, .. } if reference == "latest"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn a_missing_platform_lists_the_available_ones() {
+        let server = MockServer::start().await;
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [{
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": digest::sha256_digest(b"arm64"),
+                "size": 5,
+                "platform": {"os": "linux", "architecture": "arm64"},
+            }],
+        });
+        Mock::given(path("/v2/test/img/manifests/latest"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                serde_json::to_vec(&index).unwrap(),
+                "application/vnd.oci.image.index.v1+json",
+            ))
+            .mount(&server)
+            .await;
+
+        let err = resolve(&server, "linux/amd64").await.unwrap_err();
+
+        assert!(
+            matches!(&err, PullError::PlatformUnavailable { available, .. } if available == &["linux/arm64"]),
+            "{:#}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn a_broken_layer_fails_its_extraction() {
+        let server = MockServer::start().await;
+        let not_a_layer = testing::gzip(b"not a tar");
+        testing::serve_blob(&server, &not_a_layer).await;
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+
+        let err = pull_layers(
+            &client,
+            &server,
+            &tokens,
+            &[&not_a_layer],
+            &testing::download_options(None),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(
+            matches!(&err, PullError::Extraction { digest, .. } if *digest == digest::sha256_digest(&not_a_layer)),
+            "{:#}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn a_broken_layer_stops_the_downloads_still_running() {
+        let server = MockServer::start().await;
+        let not_a_layer = testing::gzip(b"not a tar");
+        testing::serve_blob(&server, &not_a_layer).await;
+        let slow = testing::layer(&[("slow", "")]);
+        Mock::given(method("GET"))
+            .and(path(testing::blob_path(&digest::sha256_digest(&slow))))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_bytes(slow.clone())
+                    .set_delay(Duration::from_secs(60)),
+            )
+            .mount(&server)
+            .await;
+        let client = testing::clients();
+        let tokens = testing::tokens(&client, &server);
+
+        let (layers, options): (&[&[u8]], _) =
+            (&[&not_a_layer, &slow], testing::download_options(None));
+        let pull = pull_layers(&client, &server, &tokens, layers, &options);
+        let err = tokio::time::timeout(Duration::from_secs(10), pull)
+            .await
+            .expect("the pull waited for the slow layer")
+            .unwrap_err();
+
+        assert!(matches!(err, PullError::Extraction { .. }), "{:#}", err);
+    }
+}
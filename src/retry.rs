@@ -0,0 +1,180 @@
+use anyhow::{anyhow, bail, Result};
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often and how patiently to retry registry requests that fail with a transient status or
+/// a network error.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Total time to spend waiting out 429 responses before giving up on a request
+    pub max_rate_limit_wait: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_rate_limit_wait: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff for the given (zero based) attempt, capped at `max_delay` and
+    /// jittered down to as little as half of that so parallel clients spread out.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let jitter = f64::from(nanos % 1000) / 1000.0;
+
+        delay.mul_f64(0.5 + jitter / 2.0)
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Network failures worth another try. Anything else, say an invalid URL or a body that can't be
+/// built, fails the same way every time.
+fn is_transient(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_timeout() || err.is_request()
+}
+
+/// Turns a timeout into an error that says which endpoint didn't answer, reqwest's own message
+/// buries that under its internals.
+pub fn describe(err: reqwest::Error) -> anyhow::Error {
+    match (err.is_timeout(), err.url()) {
+        (true, Some(url)) => anyhow!("Request to {} timed out", url),
+        _ => err.into(),
+    }
+}
+
+/// The delay a server asked for in `Retry-After`, when given in seconds.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Explains a 429 we won't wait out, with the quota Docker Hub reports in its headers
+/// (`100;w=21600` is 100 pulls per 6 hours).
+fn rate_limited(response: &Response, retry_after: Option<Duration>) -> anyhow::Error {
+    let mut message = format!(
+        "Rate limited by {}",
+        response.url().host_str().unwrap_or("the registry")
+    );
+    let quota: Vec<String> = ["ratelimit-remaining", "ratelimit-limit"]
+        .into_iter()
+        .filter_map(|name| {
+            let value = response.headers().get(name)?.to_str().ok()?;
+            Some(format!("{}: {}", name, value))
+        })
+        .collect();
+    if !quota.is_empty() {
+        message += &format!(" ({})", quota.join(", "));
+    }
+    if let Some(delay) = retry_after {
+        message += &format!(", it asks to wait {}s", delay.as_secs());
+    }
+    anyhow!(
+        "{}. Try again later or raise --max-rate-limit-wait, logged in pulls get a higher limit",
+        message
+    )
+}
+
+/// Sends the request built by `build`, retrying on connection errors, timeouts and 5xx
+/// responses until the policy runs out of attempts. 429 responses are waited out for as long as
+/// `max_rate_limit_wait` allows in total. Other 4xx responses are returned as is.
+pub async fn send_with_retry(
+    policy: &RetryPolicy,
+    build: impl Fn() -> RequestBuilder,
+) -> Result<Response> {
+    let mut attempt = 0;
+    let mut rate_limit_attempt = 0;
+    let mut rate_limit_waited = Duration::ZERO;
+    loop {
+        let response = match build().send().await {
+            Ok(response) => response,
+            Err(err) if is_transient(&err) && attempt < policy.max_retries => {
+                let delay = policy.backoff(attempt);
+                attempt += 1;
+                tracing::warn!(
+                    "Request failed (attempt {}/{}), retrying in {:?}: {}",
+                    attempt,
+                    policy.max_retries + 1,
+                    delay,
+                    err
+                );
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            Err(err) => return Err(describe(err)),
+        };
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            // Never spin on a `Retry-After: 0`
+            let retry_after = retry_after(&response);
+            let delay = retry_after
+                .unwrap_or_else(|| policy.backoff(rate_limit_attempt))
+                .max(policy.base_delay);
+            if rate_limit_waited + delay > policy.max_rate_limit_wait {
+                return Err(rate_limited(&response, retry_after));
+            }
+
+            rate_limit_attempt += 1;
+            rate_limit_waited += delay;
+            tracing::warn!(
+                "{} is rate limiting us, retrying in {:?}",
+                response.url(),
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            continue;
+        }
+        if !is_retryable(status) {
+            return Ok(response);
+        }
+
+        if attempt >= policy.max_retries {
+            bail!(
+                "{} failed with {} after {} retries",
+                response.url(),
+                status,
+                attempt
+            );
+        }
+
+        // 503s can come with a Retry-After too
+        let delay = retry_after(&response)
+            .map(|delay| delay.min(policy.max_delay))
+            .unwrap_or_else(|| policy.backoff(attempt));
+        attempt += 1;
+        tracing::warn!(
+            "{} answered {} (attempt {}/{}), retrying in {:?}",
+            response.url(),
+            status,
+            attempt,
+            policy.max_retries + 1,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
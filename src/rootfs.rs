@@ -0,0 +1,311 @@
+use anyhow::{Context, Result};
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, Read},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::{ffi::OsStrExt, fs::MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::{mounts, user::User};
+
+/// The directory an image is unpacked into and the container runs in. It is removed again
+/// when this is dropped, error paths included, unless it was kept. Note that
+/// `std::process::exit` doesn't run destructors.
+pub struct RootDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl RootDir {
+    pub fn create() -> Result<Self> {
+        // Canonical, so that it matches the mount targets in mountinfo when unmounting
+        let path = tempfile::tempdir()?.into_path().canonicalize()?;
+        Ok(RootDir { path, keep: false })
+    }
+
+    /// A root at a fixed `path`, reusing what is already there. It is only removed again
+    /// if not `keep`.
+    pub fn open(path: PathBuf, keep: bool) -> Result<Self> {
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve {}", path.display()))?;
+        Ok(RootDir { path, keep })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Leaves the directory in place, for whoever wants to look at it afterwards.
+    pub fn keep(mut self) -> PathBuf {
+        self.keep = true;
+        self.path.clone()
+    }
+}
+
+impl Drop for RootDir {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+
+        // Mounts first, so that nothing mounted into the container is deleted along with it
+        if let Err(err) = mounts::unmount_under(&self.path) {
+            tracing::warn!(
+                "Failed to unmount {}, leaving it in place: {:#}",
+                self.path.display(),
+                err
+            );
+            return;
+        }
+        if let Err(err) = remove_on_device(&self.path) {
+            tracing::warn!("Failed to remove {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Removes `root` like `remove_dir_all`, but only what lives on `root`'s own filesystem. A
+/// mount that somehow outlived the unmounting is left alone, along with the directories
+/// leading to it, instead of deleting whatever the host has there.
+fn remove_on_device(root: &Path) -> io::Result<()> {
+    let device = std::fs::symlink_metadata(root)?.dev();
+    if !remove_below(root, device)? {
+        return Err(io::Error::other(
+            "parts of it are on another filesystem, leaving those in place",
+        ));
+    }
+    std::fs::remove_dir(root)
+}
+
+/// Whether everything below `dir` could be removed.
+fn remove_below(dir: &Path, device: u64) -> io::Result<bool> {
+    let mut removed_all = true;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let metadata = std::fs::symlink_metadata(&path)?;
+        if metadata.dev() != device {
+            tracing::warn!(
+                "Not removing {}, it is on another filesystem",
+                path.display()
+            );
+            removed_all = false;
+        } else if metadata.is_dir() {
+            match remove_below(&path, device)? {
+                true => std::fs::remove_dir(&path)?,
+                false => removed_all = false,
+            }
+        } else {
+            std::fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(removed_all)
+}
+
+/// Everything the container's process does between fork and exec to end up inside `root`,
+/// prepared up front since allocating after a fork isn't safe.
+pub struct Entry {
+    root: CString,
+    /// The working directory and each of its ancestors, to create whichever are missing
+    workdir: Vec<CString>,
+    user: Option<User>,
+    /// The write end of the pipe behind `Failures`
+    failures: OwnedFd,
+}
+
+/// A step of `Entry::enter` whose failure the parent hears about. Only mounting /proc can fail
+/// without failing the container, the others are what `spawn` failed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    MountProc = 1,
+    CreateWorkdir = 2,
+    EnterWorkdir = 3,
+}
+
+impl Step {
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Step::MountProc),
+            2 => Some(Step::CreateWorkdir),
+            3 => Some(Step::EnterWorkdir),
+            _ => None,
+        }
+    }
+}
+
+/// Hears from the container's process what went wrong in `Entry::enter`, which it can't say
+/// itself between fork and exec. Its errors don't know which path they were about.
+pub struct Failures {
+    pipe: File,
+}
+
+impl Failures {
+    /// Each step that failed, with its error. Reads until every copy of the entry is gone,
+    /// the child's by its exec and ours with the `Command` that holds it, so drop that first.
+    pub fn read(mut self) -> io::Result<Vec<(Step, io::Error)>> {
+        let mut records = Vec::new();
+        self.pipe.read_to_end(&mut records)?;
+        Ok(records
+            .chunks_exact(5)
+            .filter_map(|record| {
+                let errno = i32::from_ne_bytes(record[1..].try_into().unwrap());
+                Some((
+                    Step::from_tag(record[0])?,
+                    io::Error::from_raw_os_error(errno),
+                ))
+            })
+            .collect())
+    }
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Path {} contains a NUL byte", path.display()))
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+impl Entry {
+    /// `workdir` is the path inside the container, relative ones are taken from its root.
+    pub fn new(root: &Path, workdir: &str, user: Option<User>) -> Result<(Self, Failures)> {
+        let workdir = Path::new("/").join(workdir);
+        let mut ancestors = workdir
+            .ancestors()
+            .map(c_path)
+            .collect::<Result<Vec<_>>>()?;
+        ancestors.reverse();
+
+        // Close-on-exec, or the container's command would keep the parent reading
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to create a pipe");
+        }
+        let (read, write) = unsafe { (File::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        let entry = Entry {
+            root: c_path(root)?,
+            workdir: ancestors,
+            user,
+            failures: write,
+        };
+        Ok((entry, Failures { pipe: read }))
+    }
+
+    /// Tells `Failures` that `step` failed. Only what's safe between fork and exec.
+    fn report(&self, step: Step, err: &io::Error) {
+        let mut record = [step as u8; 5];
+        record[1..].copy_from_slice(&err.raw_os_error().unwrap_or(0).to_ne_bytes());
+        // There's no one else to tell if this fails too
+        unsafe {
+            libc::write(
+                self.failures.as_raw_fd(),
+                record.as_ptr().cast(),
+                record.len(),
+            )
+        };
+    }
+
+    /// Chroots into the root and mounts its /proc, then creates the working directory like
+    /// Docker does when the image doesn't have it, and finally drops to the image's user. The
+    /// container can do without /proc, failing to mount it is only reported.
+    pub fn enter(&self) -> io::Result<()> {
+        unsafe {
+            check(libc::chroot(self.root.as_ptr()))?;
+            check(libc::chdir(c"/".as_ptr()))?;
+        }
+        if let Err(err) = mounts::mount_proc() {
+            self.report(Step::MountProc, &err);
+        }
+
+        for dir in &self.workdir {
+            if unsafe { libc::mkdir(dir.as_ptr(), 0o755) } != 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::AlreadyExists {
+                    self.report(Step::CreateWorkdir, &err);
+                    return Err(err);
+                }
+            }
+        }
+
+        if let Some(user) = &self.user {
+            user.switch()?;
+        }
+        if let Some(workdir) = self.workdir.last() {
+            if let Err(err) = unsafe { check(libc::chdir(workdir.as_ptr())) } {
+                self.report(Step::EnterWorkdir, &err);
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removing_the_root_does_not_follow_symlinks() {
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("keep"), "").unwrap();
+
+        let root = RootDir::create().unwrap();
+        let path = root.path().to_owned();
+        std::fs::create_dir_all(path.join("a/b")).unwrap();
+        std::fs::write(path.join("a/b/file"), "").unwrap();
+        std::os::unix::fs::symlink(outside.path(), path.join("a/link")).unwrap();
+        drop(root);
+
+        assert!(!path.exists());
+        assert!(outside.path().join("keep").exists());
+    }
+
+    #[test]
+    fn roots_are_canonical() {
+        let parent = tempfile::tempdir().unwrap();
+        let root = RootDir::open(parent.path().join("x/../root"), true).unwrap();
+        assert_eq!(
+            root.path(),
+            parent.path().canonicalize().unwrap().join("root")
+        );
+    }
+
+    #[test]
+    fn failures_reach_the_parent_once_the_entry_is_gone() {
+        let root = tempfile::tempdir().unwrap();
+        let (entry, failures) = Entry::new(root.path(), "/", None).unwrap();
+        entry.report(Step::MountProc, &io::Error::from_raw_os_error(libc::EPERM));
+        entry.report(
+            Step::EnterWorkdir,
+            &io::Error::from_raw_os_error(libc::EACCES),
+        );
+        drop(entry);
+
+        let failures = failures.read().unwrap();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].0, Step::MountProc);
+        assert_eq!(failures[0].1.raw_os_error(), Some(libc::EPERM));
+        assert_eq!(failures[1].0, Step::EnterWorkdir);
+        assert_eq!(failures[1].1.raw_os_error(), Some(libc::EACCES));
+    }
+
+    #[test]
+    fn no_failures_read_as_none() {
+        let root = tempfile::tempdir().unwrap();
+        let (entry, failures) = Entry::new(root.path(), "/", None).unwrap();
+        drop(entry);
+        assert!(failures.read().unwrap().is_empty());
+    }
+}
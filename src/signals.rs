@@ -0,0 +1,51 @@
+use anyhow::Result;
+use std::time::Duration;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+/// Passes SIGINT and SIGTERM on to the container instead of letting them kill us and leave it
+/// running. Handlers are installed up front, so a signal that arrives while the container is
+/// starting is held until it can be forwarded.
+pub struct Forwarder {
+    interrupt: Signal,
+    terminate: Signal,
+}
+
+impl Forwarder {
+    pub fn install() -> Result<Self> {
+        Ok(Forwarder {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
+
+    /// Forwards signals to `pid` from now on. As the init of its PID namespace the process
+    /// ignores any signal it has no handler for, so once the first one arrives it has
+    /// `stop_timeout` to exit before it is killed, like `docker stop`.
+    pub fn start(mut self, pid: u32, stop_timeout: Duration) {
+        let pid = pid as libc::pid_t;
+        tokio::spawn(async move {
+            let mut kill_armed = false;
+            loop {
+                let signal = tokio::select! {
+                    Some(()) = self.interrupt.recv() => libc::SIGINT,
+                    Some(()) = self.terminate.recv() => libc::SIGTERM,
+                    else => return,
+                };
+                tracing::debug!("Forwarding signal {} to the container", signal);
+                unsafe { libc::kill(pid, signal) };
+
+                if !kill_armed {
+                    kill_armed = true;
+                    tokio::spawn(async move {
+                        tokio::time::sleep(stop_timeout).await;
+                        tracing::warn!(
+                            "Container didn't stop within {:?}, killing it",
+                            stop_timeout
+                        );
+                        unsafe { libc::kill(pid, libc::SIGKILL) };
+                    });
+                }
+            }
+        });
+    }
+}
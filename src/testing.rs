@@ -0,0 +1,135 @@
+//! Fixtures for the tests: layers built in memory and a mock registry that serves them.
+
+use std::{io::Write, time::Duration};
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use crate::{
+    auth::TokenManager,
+    cache::BlobCache,
+    digest, http,
+    registry::{Descriptor, DownloadOptions},
+    retry::RetryPolicy,
+};
+
+pub const REPOSITORY: &str = "test/img";
+
+pub const GZIP_LAYER: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+
+/// A gzipped layer holding `files`, as paths and contents.
+pub fn layer(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        builder
+            .append_data(&mut header, path, contents.as_bytes())
+            .unwrap();
+    }
+    gzip(&builder.into_inner().unwrap())
+}
+
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+pub fn descriptor(data: &[u8]) -> Descriptor {
+    Descriptor {
+        media_type: Some(GZIP_LAYER.to_owned()),
+        digest: digest::sha256_digest(data),
+        size: data.len() as u64,
+        urls: Vec::new(),
+    }
+}
+
+/// Retries that don't slow the tests down.
+pub fn fast_retries() -> RetryPolicy {
+    RetryPolicy {
+        max_retries: 2,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(10),
+        max_rate_limit_wait: Duration::from_secs(1),
+    }
+}
+
+pub fn download_options(cache: Option<&BlobCache>) -> DownloadOptions<'_> {
+    DownloadOptions {
+        max_concurrent_downloads: 3,
+        cache,
+        quiet: true,
+        read_timeout: Duration::from_secs(5),
+        max_unpacked_size: u64::MAX,
+        applied: None,
+    }
+}
+
+pub fn clients() -> http::Clients {
+    reqwest::Client::new().into()
+}
+
+pub fn tokens(client: &http::Clients, server: &MockServer) -> TokenManager {
+    TokenManager::new(
+        client,
+        &server.uri(),
+        REPOSITORY,
+        None,
+        fast_retries(),
+        None,
+    )
+}
+
+pub fn blob_path(digest: &str) -> String {
+    format!("/v2/{}/blobs/{}", REPOSITORY, digest)
+}
+
+/// Serves `data` as the blob it is the content of, returning its digest.
+pub async fn serve_blob(server: &MockServer, data: &[u8]) -> String {
+    let digest = digest::sha256_digest(data);
+    Mock::given(method("GET"))
+        .and(path(blob_path(&digest)))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(data))
+        .mount(server)
+        .await;
+    digest
+}
+
+/// Serves a single platform image at `latest`, made of `layers` and the given config.
+pub async fn serve_image(server: &MockServer, layers: &[Vec<u8>], config: serde_json::Value) {
+    let config = serde_json::to_vec(&config).unwrap();
+    let config_digest = serve_blob(server, &config).await;
+    let mut descriptors = Vec::new();
+    for layer in layers {
+        serve_blob(server, layer).await;
+        descriptors.push(serde_json::json!({
+            "mediaType": GZIP_LAYER,
+            "digest": digest::sha256_digest(layer),
+            "size": layer.len(),
+        }));
+    }
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "digest": config_digest,
+            "size": config.len(),
+        },
+        "layers": descriptors,
+    });
+    Mock::given(method("GET"))
+        .and(path(format!("/v2/{}/manifests/latest", REPOSITORY)))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            serde_json::to_vec(&manifest).unwrap(),
+            "application/vnd.oci.image.manifest.v1+json",
+        ))
+        .mount(server)
+        .await;
+}
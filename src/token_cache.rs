@@ -0,0 +1,91 @@
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::cache::state_dir;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Entry {
+    token: String,
+    /// Unix time in seconds
+    expires_at: u64,
+}
+
+/// Registry tokens saved between runs, so that back to back pulls don't each go through the
+/// auth server. The file only ever holds tokens that are still valid and is only readable by
+/// us, a token is as good as the credentials for as long as it lasts.
+pub struct TokenCache {
+    path: PathBuf,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl TokenCache {
+    pub fn new(path: &Path) -> Self {
+        TokenCache {
+            path: path.to_owned(),
+        }
+    }
+
+    pub fn default_path() -> PathBuf {
+        state_dir().join("token-cache.json")
+    }
+
+    fn read(&self) -> HashMap<String, Entry> {
+        std::fs::read(&self.path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    fn write(&self, entries: &HashMap<String, Entry>) -> Result<()> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(dir)?;
+
+        // Temp files are created 0600, and renaming means a concurrent pull never reads half
+        // a file. Two pulls saving at once can still lose one token, which is just a cache miss.
+        let temp_file = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer(temp_file.as_file(), entries)?;
+        temp_file.persist(&self.path)?;
+
+        Ok(())
+    }
+
+    /// The cached token for `key` and how much longer it is valid.
+    pub fn get(&self, key: &str) -> Option<(String, Duration)> {
+        let entry = self.read().remove(key)?;
+        let remaining = entry.expires_at.checked_sub(now())?;
+        Some((entry.token, Duration::from_secs(remaining)))
+    }
+
+    /// Saves a token valid for another `valid_for`, dropping expired ones while at it. Failing
+    /// to save only costs a round trip next time, so it is never an error.
+    pub fn put(&self, key: &str, token: &str, valid_for: Duration) {
+        let now = now();
+        let mut entries = self.read();
+        entries.retain(|_, entry| entry.expires_at > now);
+        entries.insert(
+            key.to_owned(),
+            Entry {
+                token: token.to_owned(),
+                expires_at: now + valid_for.as_secs(),
+            },
+        );
+
+        if let Err(err) = self.write(&entries) {
+            tracing::warn!(
+                "Failed to save token cache {}: {:#}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
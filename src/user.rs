@@ -0,0 +1,116 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    io::{self, Read},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+use docker_starter_rust::layer::resolve_in_root;
+
+/// Who the container's command runs as, resolved against the image's own user database.
+#[derive(Debug, Clone)]
+pub struct User {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups, from the groups in `/etc/group` that list the user
+    pub groups: Vec<u32>,
+}
+
+/// Reads a file of the image the way the container would see it, minus a symlink as the file
+/// itself, which might just as well point at the host's.
+fn read_in_root(root: &Path, path: &str) -> Option<String> {
+    let mut contents = String::new();
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(resolve_in_root(root, Path::new(path)).ok()?)
+        .ok()?
+        .read_to_string(&mut contents)
+        .ok()?;
+    Some(contents)
+}
+
+/// The lines of `/etc/passwd` or `/etc/group` in `root`, split into their fields.
+fn entries(root: &Path, path: &str) -> Vec<Vec<String>> {
+    // Images built from scratch often have neither file, which is fine for numeric ids
+    let contents = read_in_root(root, path).unwrap_or_default();
+    contents
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(':').map(str::to_owned).collect())
+        .collect()
+}
+
+fn field(entry: &[String], index: usize) -> Option<u32> {
+    entry.get(index)?.parse().ok()
+}
+
+/// Resolves a Docker `User` value, `user`, `user:group` or either as a numeric id, against the
+/// `/etc/passwd` and `/etc/group` of the image unpacked in `root`. Names have to exist in the
+/// image, a numeric uid doesn't and then gets group 0 like in Docker.
+pub fn resolve(root: &Path, spec: &str) -> Result<User> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+
+    let passwd = entries(root, "etc/passwd");
+    let account = passwd.iter().find(|entry| match user.parse::<u32>() {
+        Ok(uid) => field(entry, 2) == Some(uid),
+        Err(_) => entry[0] == user,
+    });
+    let (name, uid, primary_gid) = match (account, user.parse::<u32>()) {
+        (Some(entry), _) => (
+            Some(entry[0].as_str()),
+            field(entry, 2).context("Malformed /etc/passwd entry")?,
+            field(entry, 3).context("Malformed /etc/passwd entry")?,
+        ),
+        (None, Ok(uid)) => (None, uid, 0),
+        (None, Err(_)) => bail!("User '{}' doesn't exist in the image", user),
+    };
+
+    let group_entries = entries(root, "etc/group");
+    let gid = match group {
+        None => primary_gid,
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => group_entries
+                .iter()
+                .find(|entry| entry[0] == group)
+                .and_then(|entry| field(entry, 2))
+                .with_context(|| format!("Group '{}' doesn't exist in the image", group))?,
+        },
+    };
+
+    let mut groups = vec![gid];
+    if let Some(name) = name {
+        for entry in &group_entries {
+            let member = entry
+                .get(3)
+                .is_some_and(|members| members.split(',').any(|member| member == name));
+            match field(entry, 2) {
+                Some(gid) if member && !groups.contains(&gid) => groups.push(gid),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(User { uid, gid, groups })
+}
+
+impl User {
+    /// Drops from root to this user, groups first while we still may change them. Only calls
+    /// async-signal-safe functions, so it can run between fork and exec.
+    pub fn switch(&self) -> io::Result<()> {
+        unsafe {
+            if libc::setgroups(self.groups.len(), self.groups.as_ptr()) != 0
+                || libc::setgid(self.gid) != 0
+                || libc::setuid(self.uid) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+}
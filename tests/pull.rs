@@ -0,0 +1,128 @@
+//! Pulls through the public API from a mock registry, the way a user of the library would.
+
+use docker_starter_rust::{digest, pull_image, PullError, PullOptions};
+use std::io::Write;
+use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+};
+
+const REPOSITORY: &str = "test/img";
+
+fn layer(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        builder
+            .append_data(&mut header, path, contents.as_bytes())
+            .unwrap();
+    }
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(&builder.into_inner().unwrap()).unwrap();
+    encoder.finish().unwrap()
+}
+
+async fn serve(server: &MockServer, reference: &str, body: Vec<u8>, media_type: &str) {
+    Mock::given(method("GET"))
+        .and(path(format!("/v2/{}/{}", REPOSITORY, reference)))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(body, media_type))
+        .mount(server)
+        .await;
+}
+
+/// Serves an image made of `layers` at `latest`, returning its manifest's digest.
+async fn serve_image(server: &MockServer, layers: &[Vec<u8>], config: serde_json::Value) -> String {
+    let config = serde_json::to_vec(&config).unwrap();
+    let config_digest = digest::sha256_digest(&config);
+    let mut descriptors = Vec::new();
+    for layer in layers {
+        let layer_digest = digest::sha256_digest(layer);
+        descriptors.push(serde_json::json!({
+            "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+            "digest": layer_digest,
+            "size": layer.len(),
+        }));
+        let blob = format!("blobs/{}", layer_digest);
+        serve(server, &blob, layer.clone(), "application/octet-stream").await;
+    }
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "digest": config_digest,
+            "size": config.len(),
+        },
+        "layers": descriptors,
+    });
+    let manifest = serde_json::to_vec(&manifest).unwrap();
+    let manifest_digest = digest::sha256_digest(&manifest);
+    let blob = format!("blobs/{}", config_digest);
+    serve(server, &blob, config, "application/octet-stream").await;
+    let media_type = "application/vnd.oci.image.manifest.v1+json";
+    serve(server, "manifests/latest", manifest, media_type).await;
+    manifest_digest
+}
+
+/// Keeps the pull away from the user's cache, token cache and image store.
+fn options() -> PullOptions {
+    PullOptions {
+        no_cache: true,
+        no_token_cache: true,
+        quiet: true,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn pulls_and_unpacks_an_image() {
+    let server = MockServer::start().await;
+    let layers = [
+        layer(&[("etc/os-release", "ID=test\n"), ("bin/hello", "v1")]),
+        layer(&[("bin/hello", "v2"), ("etc/.wh.os-release", "")]),
+    ];
+    let config = serde_json::json!({"config": {"Cmd": ["/bin/hello"], "Env": ["A=1"]}});
+    let manifest_digest = serve_image(&server, &layers, config).await;
+    let root = tempfile::tempdir().unwrap();
+
+    let image = pull_image(
+        &format!("{}/{}", server.address(), REPOSITORY),
+        root.path(),
+        &options(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(image.digest, manifest_digest);
+    assert_eq!(image.config.command_line(None, &[]), ["/bin/hello"]);
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("bin/hello")).unwrap(),
+        "v2"
+    );
+    assert!(!root.path().join("etc/os-release").exists());
+}
+
+#[tokio::test]
+async fn a_missing_image_fails_with_its_reference() {
+    let server = MockServer::start().await;
+    let root = tempfile::tempdir().unwrap();
+
+    let err = pull_image(
+        &format!("{}/{}:nope", server.address(), REPOSITORY),
+        root.path(),
+        &options(),
+    )
+    .await
+    .unwrap_err();
+
+    assert!(
+        matches!(&err, PullError::ManifestNotFound { reference, .. } if reference == "nope"),
+        "{:#}",
+        err
+    );
+}